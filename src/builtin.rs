@@ -0,0 +1,26 @@
+//! Presets compiled into the binary, selected as `builtin:<name>`, for environments
+//! where `starship preset` is unavailable.
+
+/// Prefix of a preset name that selects a built-in preset.
+pub const PREFIX: &str = "builtin:";
+
+/// Built-in presets by name.
+pub const PRESETS: &[(&str, &str)] = &[
+    ("ascii", include_str!("presets/ascii.toml")),
+    ("minimal", include_str!("presets/minimal.toml")),
+];
+
+/// The TOML of the built-in preset `name` (without the prefix).
+pub fn preset(name: &str) -> Result<&'static str, String> {
+    PRESETS
+        .iter()
+        .find(|(preset, _)| *preset == name)
+        .map(|(_, content)| *content)
+        .ok_or_else(|| {
+            let available: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+            format!(
+                "no built-in preset `{name}` (available: {})",
+                available.join(", ")
+            )
+        })
+}
@@ -0,0 +1,217 @@
+//! Cache directory and atomic cache file writes.
+
+use std::{
+    env, fs,
+    hash::Hasher,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Once,
+    thread,
+    time::Duration,
+};
+
+use crate::{path_err, paths::expand_path};
+
+pub fn cache_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Some(dir) = env::var_os("STARSHIP_MULTI_CACHE_DIR") {
+        return Ok(expand_path(Path::new(&dir)));
+    }
+    Ok(dirs::cache_dir()
+        .ok_or("could not determine cache directory")?
+        .join("starship-multi-config"))
+}
+
+/// The cache directory to use, or `None` to bypass the cache and write temp files
+/// instead: in read-only mode, or when no cache directory can be determined (e.g. no
+/// home directory in a stripped-down container), which is warned about once.
+pub fn usable_cache_dir(readonly: bool) -> Option<PathBuf> {
+    static WARNED: Once = Once::new();
+
+    if readonly {
+        return None;
+    }
+    cache_dir()
+        .inspect_err(|e| {
+            WARNED.call_once(|| {
+                eprintln!("starship-multi-config: warning: {e}; using temporary files instead");
+            });
+        })
+        .ok()
+}
+
+/// How many times to retry a cache write that failed with a transient error.
+const WRITE_RETRIES: u32 = 3;
+
+/// Atomically write `content` to the cache file at `path` and return the path.
+///
+/// Transient errors are retried with a short backoff. If the cache still can't be
+/// written, the content goes to a fresh file in the temp directory instead (whose path
+/// is returned) so the prompt keeps working.
+pub fn write_cache(path: &Path, content: &[u8]) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    write_cache_with(path, content, try_write_cache)
+}
+
+/// [`write_cache`] with the function making each attempt, so tests can inject errors.
+fn write_cache_with(
+    path: &Path,
+    content: &[u8],
+    mut try_write: impl FnMut(&Path, &[u8]) -> io::Result<()>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut attempt = 0;
+    let err = loop {
+        match try_write(path, content) {
+            Ok(()) => return Ok(path.to_path_buf()),
+            Err(e)
+                if attempt < WRITE_RETRIES
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+                    ) =>
+            {
+                attempt += 1;
+                log::debug!("retrying cache write to {}: {e}", path.display());
+                thread::sleep(Duration::from_millis(10 << attempt));
+            }
+            Err(e) => break e,
+        }
+    };
+
+    eprintln!(
+        "starship-multi-config: warning: {}; using a temporary file instead",
+        path_err(path, err)
+    );
+    write_temp(content)
+}
+
+/// Write `content` to a new file in the temp directory that outlives this process.
+pub fn write_temp(content: &[u8]) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut tmp = tempfile::Builder::new()
+        .prefix("starship-multi-config-")
+        .suffix(".toml")
+        .tempfile()?;
+    tmp.write_all(content)?;
+    let (_, tmp_path) = tmp.keep()?;
+    Ok(tmp_path)
+}
+
+fn try_write_cache(path: &Path, content: &[u8]) -> io::Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| io::Error::other("cache file has no parent directory"))?;
+    fs::create_dir_all(dir)?;
+    let tmp = tempfile::NamedTempFile::new_in(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tmp.as_file()
+            .set_permissions(fs::Permissions::from_mode(cache_file_mode()))?;
+    }
+    fs::write(tmp.path(), content)?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Permission bits for cache files: `0o666` without the bits in the octal
+/// `STARSHIP_MULTI_UMASK`, which defaults to `077`. Merged configs can hold secrets in
+/// custom commands, so by default only the owner can read them.
+#[cfg(unix)]
+fn cache_file_mode() -> u32 {
+    const DEFAULT_UMASK: u32 = 0o077;
+    let umask = match env::var("STARSHIP_MULTI_UMASK") {
+        Ok(umask) => u32::from_str_radix(umask.trim_start_matches("0o"), 8).unwrap_or_else(|_| {
+            eprintln!(
+                "starship-multi-config: warning: invalid STARSHIP_MULTI_UMASK `{umask}`, \
+                 using {DEFAULT_UMASK:03o}"
+            );
+            DEFAULT_UMASK
+        }),
+        Err(_) => DEFAULT_UMASK,
+    };
+    0o666 & !umask
+}
+
+/// Hasher for cache keys. Keys only need to be stable between runs of the same build,
+/// since a different build may also merge differently.
+#[cfg(not(feature = "xxhash"))]
+type KeyHasher = std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "xxhash")]
+type KeyHasher = twox_hash::XxHash64;
+
+/// Version of the cache format, part of every cache key. Bump it when a change to
+/// merging or rendering would make existing cache files stale, so an upgrade doesn't
+/// serve them.
+const CACHE_VERSION: u32 = 1;
+
+pub fn hash_key(
+    f: impl FnOnce(&mut KeyHasher) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    versioned_hash_key(CACHE_VERSION, f)
+}
+
+fn versioned_hash_key(
+    version: u32,
+    f: impl FnOnce(&mut KeyHasher) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut h = KeyHasher::default();
+    h.write_u32(version);
+    f(&mut h)?;
+    Ok(format!("{:x}", h.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::Hash;
+
+    use super::*;
+
+    #[test]
+    fn write_cache_retries_transient_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("merged.toml");
+
+        let mut attempts = 0;
+        let written = write_cache_with(&path, b"add_newline = false\n", |path, content| {
+            attempts += 1;
+            if attempts == 1 {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            try_write_cache(path, content)
+        })
+        .unwrap();
+
+        assert_eq!(attempts, 2);
+        assert_eq!(written, path);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "add_newline = false\n");
+    }
+
+    #[test]
+    fn hash_key_is_stable() {
+        let key = || {
+            hash_key(|h| {
+                Path::new("/home/user/.config/starship.toml").hash(h);
+                "STARSHIP_SHELL".hash(h);
+                Ok(())
+            })
+            .unwrap()
+        };
+
+        // `DefaultHasher::new` is only guaranteed to be the same within a build, while
+        // xxHash is a fixed algorithm
+        assert_eq!(key(), key());
+        #[cfg(feature = "xxhash")]
+        assert_eq!(key(), "d72eb8467976efe6");
+    }
+
+    #[test]
+    fn cache_version_changes_keys() {
+        let key = |version| {
+            versioned_hash_key(version, |h| {
+                Path::new("/home/user/.config/starship.toml").hash(h);
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert_eq!(key(CACHE_VERSION), key(CACHE_VERSION));
+        assert_ne!(key(CACHE_VERSION), key(CACHE_VERSION + 1));
+    }
+}
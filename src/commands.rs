@@ -0,0 +1,861 @@
+//! Subcommands other than the default merge.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::CommandFactory;
+use sha2::{Digest, Sha256};
+use toml_edit::{Decor, DocumentMut, Item};
+
+use crate::{
+    Cli, Format, Output, Sources, builtin,
+    cache::cache_dir,
+    known_keys,
+    merge::{
+        by_priority, is_fill_only, is_toml, merge_files, merge_layer, parse_config, read_config,
+        sanitize, strip_includes, substitute_vars,
+    },
+    merge_cached, passes_through, path_err,
+    paths::expand_path,
+    print_merged, render,
+    sources::{
+        SourceKind, default_sources, primary_config, resolve_source_kinds, resolve_sources,
+        run_starship,
+    },
+    verify,
+};
+
+pub fn bench(sources: &Sources, output: &Output) -> Result<(), Box<dyn std::error::Error>> {
+    let paths = resolve_sources(sources)?;
+
+    let start = Instant::now();
+    merge_cached(&paths, sources, output, true)?;
+    let cold = start.elapsed();
+
+    let start = Instant::now();
+    merge_cached(&paths, sources, output, false)?;
+    let warm = start.elapsed();
+
+    println!("cold merge ({} sources): {cold:?}", paths.len());
+    println!("warm cache hit: {warm:?}");
+    Ok(())
+}
+
+pub fn fingerprint(sources: &Sources) -> Result<(), Box<dyn std::error::Error>> {
+    let paths = resolve_sources(sources)?;
+    let merged = merge_files(&paths, sources.type_check, sources.merge_max_depth)?;
+
+    // `toml::Table` keeps keys sorted, so the serialized form is deterministic
+    let digest = Sha256::digest(toml::to_string(&merged)?);
+    println!("{digest:x}");
+    Ok(())
+}
+
+pub fn report(sources: &Sources) -> Result<(), Box<dyn std::error::Error>> {
+    let paths = resolve_sources(sources)?;
+    let tables = paths
+        .iter()
+        .map(|path| parse_config(path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Compare the leaf keys before and after each layer, which sees the effect of
+    // every merge setting without tracking them separately
+    let mut merged = toml::Table::new();
+    let mut overridden = BTreeSet::new();
+    let mut deleted = 0;
+    let mut contributions = Vec::new();
+    for (path, table) in by_priority(&paths, tables) {
+        let before = leaf_keys(&merged);
+        let set = leaf_keys(&table);
+        merge_layer(&mut merged, path, &table, false, sources.merge_max_depth);
+        let after = leaf_keys(&merged);
+
+        let added = after.difference(&before).count();
+        // A fill-only layer never replaces a value that was already set
+        let replaced: Vec<String> = set
+            .into_iter()
+            .filter(|k| !is_fill_only(&table) && before.contains(k) && after.contains(k))
+            .collect();
+        deleted += before.difference(&after).count();
+        contributions.push((path, added, replaced.len()));
+        overridden.extend(replaced);
+    }
+    substitute_vars(&mut merged);
+    sanitize(&mut merged);
+    let keys = leaf_keys(&merged);
+
+    println!("keys: {}", keys.len());
+    println!("overridden: {}", keys.intersection(&overridden).count());
+    println!("deleted: {deleted}");
+    println!("sources:");
+    for (path, added, replaced) in contributions {
+        println!("  {}: {added} added, {replaced} overridden", path.display());
+    }
+    Ok(())
+}
+
+/// The dotted key paths of the non-table values in `table`, leaving out top-level
+/// reserved keys like `__vars__`.
+fn leaf_keys(table: &toml::Table) -> BTreeSet<String> {
+    fn walk(table: &toml::Table, prefix: &str, out: &mut BTreeSet<String>) {
+        for (key, value) in table {
+            if prefix.is_empty() && key.starts_with("__") && key.ends_with("__") {
+                continue;
+            }
+            let path = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{prefix}.{key}")
+            };
+            match value {
+                toml::Value::Table(table) => walk(table, &path, out),
+                _ => {
+                    out.insert(path);
+                }
+            }
+        }
+    }
+
+    let mut out = BTreeSet::new();
+    walk(table, "", &mut out);
+    out
+}
+
+pub fn schema(sources: &Sources) -> Result<(), Box<dyn std::error::Error>> {
+    let paths = resolve_sources(sources)?;
+    let merged = merge_files(&paths, sources.type_check, sources.merge_max_depth)?;
+
+    let mut schema = value_schema(&toml::Value::Table(merged));
+    schema.insert(
+        "$schema".into(),
+        "https://json-schema.org/draft/2020-12/schema".into(),
+    );
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+/// A JSON Schema matching the structure of `value`.
+fn value_schema(value: &toml::Value) -> serde_json::Map<String, serde_json::Value> {
+    let mut schema = serde_json::Map::new();
+    let kind = match value {
+        toml::Value::String(_) => "string",
+        toml::Value::Integer(_) => "integer",
+        toml::Value::Float(_) => "number",
+        toml::Value::Boolean(_) => "boolean",
+        toml::Value::Datetime(_) => {
+            schema.insert("format".into(), "date-time".into());
+            "string"
+        }
+        toml::Value::Array(values) => {
+            if let Some(first) = values.first() {
+                schema.insert("items".into(), value_schema(first).into());
+            }
+            "array"
+        }
+        toml::Value::Table(table) => {
+            let properties = table
+                .iter()
+                .map(|(key, value)| (key.clone(), value_schema(value).into()))
+                .collect::<serde_json::Map<_, _>>();
+            schema.insert("properties".into(), properties.into());
+            "object"
+        }
+    };
+    schema.insert("type".into(), kind.into());
+    schema
+}
+
+pub fn explain(sources: &Sources, format: Format) -> Result<(), Box<dyn std::error::Error>> {
+    let resolved = resolve_source_kinds(sources)?;
+    let paths: Vec<PathBuf> = resolved.iter().map(|(path, _)| path.clone()).collect();
+
+    // Track which files contributed to each top-level key. A key that is replaced
+    // (rather than deep-merged) forgets the files that set it before.
+    let mut merged = toml::Table::new();
+    let mut provenance: BTreeMap<&str, Vec<&Path>> = BTreeMap::new();
+    let mut documents = Vec::new();
+    let tables = paths
+        .iter()
+        .map(|path| parse_config(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let layers = by_priority(&paths, tables);
+    for (path, table) in &layers {
+        let before = merged.clone();
+        merge_layer(&mut merged, path, table, false, sources.merge_max_depth);
+        for (key, value) in table {
+            // A fill-only layer doesn't contribute keys that were already set
+            if is_fill_only(table) && merged.get(key) == before.get(key) {
+                continue;
+            }
+            let from = provenance.entry(key).or_default();
+            if !(value.is_table() && before.get(key).is_some_and(toml::Value::is_table)) {
+                from.clear();
+            }
+            from.push(path);
+        }
+
+        // Parse again with `toml_edit`, which keeps comments. Other formats' aren't kept.
+        if !is_toml(path) {
+            documents.push(DocumentMut::new());
+        } else {
+            let content = read_config(path).map_err(|e| path_err(path, e))?;
+            documents.push(content.parse::<DocumentMut>()?);
+        }
+    }
+    substitute_vars(&mut merged);
+    sanitize(&mut merged);
+
+    if format == Format::Json {
+        let layers: Vec<(PathBuf, SourceKind)> = layers
+            .iter()
+            .map(|(path, _)| {
+                let kind = resolved
+                    .iter()
+                    .find_map(|(p, kind)| (p == path).then_some(*kind))
+                    .unwrap_or(SourceKind::Config);
+                (path.to_path_buf(), kind)
+            })
+            .collect();
+        let keys: serde_json::Map<_, _> = merged
+            .keys()
+            .map(|key| (key.clone(), serde_json::json!(provenance[key.as_str()])))
+            .collect();
+        let report = serde_json::json!({ "sources": sources_json(&layers), "keys": keys });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    // `toml` drops comments, so re-render the merge result with `toml_edit` and carry
+    // over comments from the sources, then annotate each top-level key.
+    let mut out = toml::to_string(&merged)?.parse::<DocumentMut>()?;
+    let source_tables: Vec<&toml_edit::Table> = documents.iter().map(|d| d.as_table()).collect();
+    copy_comments(out.as_table_mut(), &source_tables);
+
+    for (i, (mut key, item)) in out.as_table_mut().iter_mut().enumerate() {
+        let from = format!(
+            "# from {}\n",
+            provenance[key.get()]
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        match item {
+            Item::Table(table) => {
+                // Implicit tables have no header line to attach the comment to
+                table.set_implicit(false);
+                let decor = table.decor_mut();
+                let blank = if i == 0 { "" } else { "\n" };
+                decor.set_prefix(format!("{blank}{from}{}", comments(decor)));
+            }
+            _ => {
+                let decor = key.leaf_decor_mut();
+                decor.set_prefix(format!("{}{from}{}", leading_blank(decor), comments(decor)));
+            }
+        }
+    }
+
+    print!("{out}");
+    Ok(())
+}
+
+/// Copy the comments above (and trailing) each key in `out` from the last source table
+/// that sets it. `sources` are the tables at the same path in each source, in order.
+fn copy_comments(out: &mut toml_edit::Table, sources: &[&toml_edit::Table]) {
+    for (mut key, item) in out.iter_mut() {
+        let Some((source_key, source_item)) = sources
+            .iter()
+            .rev()
+            .find_map(|table| table.get_key_value(key.get()))
+        else {
+            continue;
+        };
+
+        match (item, source_item) {
+            (Item::Table(table), _) => {
+                let nested: Vec<&toml_edit::Table> = sources
+                    .iter()
+                    .filter_map(|table| table.get(key.get())?.as_table())
+                    .collect();
+                // Tables are deep-merged, so keep the header comment from the last layer
+                // that wrote one rather than the last layer that touched the table
+                if let Some(header) = nested
+                    .iter()
+                    .rev()
+                    .map(|table| comments(table.decor()))
+                    .find(|comments| !comments.is_empty())
+                {
+                    let prefix = leading_blank(table.decor()) + &header;
+                    table.decor_mut().set_prefix(prefix);
+                }
+                copy_comments(table, &nested);
+            }
+            (Item::Value(value), Item::Value(source_value)) => {
+                let prefix = leading_blank(key.leaf_decor()) + &comments(source_key.leaf_decor());
+                key.leaf_decor_mut().set_prefix(prefix);
+                if let Some(suffix) = source_value.decor().suffix().and_then(|s| s.as_str())
+                    && suffix.contains('#')
+                {
+                    value.decor_mut().set_suffix(suffix);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The comment lines in a decor prefix, without surrounding blank lines.
+fn comments(decor: &Decor) -> String {
+    let prefix = decor.prefix().and_then(|p| p.as_str()).unwrap_or_default();
+    prefix
+        .lines()
+        .filter(|line| line.trim_start().starts_with('#'))
+        .map(|line| format!("{}\n", line.trim_start()))
+        .collect()
+}
+
+/// The blank lines leading a decor prefix, which separate it from the previous item.
+fn leading_blank(decor: &Decor) -> String {
+    let prefix = decor.prefix().and_then(|p| p.as_str()).unwrap_or_default();
+    prefix
+        .lines()
+        .take_while(|line| line.trim().is_empty())
+        .map(|_| "\n")
+        .collect()
+}
+
+pub fn edit(sources: &Sources, output: &Output) -> Result<(), Box<dyn std::error::Error>> {
+    // Presets, stdin, and drop-ins aren't what the user means to edit, and compressed
+    // files can't be edited in place, so the primary layer is the last config argument
+    let primary = primary_config(sources)?
+        .ok_or("no config file to edit; pass the file to edit as the last config")?;
+
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // `$EDITOR` may carry arguments, e.g. `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words.next().ok_or("$EDITOR is empty")?;
+    let status = Command::new(program)
+        .args(words)
+        .arg(&primary)
+        .status()
+        .map_err(|e| format!("{program}: {e}"))?;
+    if !status.success() {
+        return Err(format!("{editor} exited with {status}").into());
+    }
+
+    print_merged(sources, output)
+}
+
+pub fn watch(
+    sources: &Sources,
+    output: &Output,
+    interval: Duration,
+    exec: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Sources are resolved on every check, so files that a glob or drop-in directory
+    // newly matches are picked up too
+    let mut last = None;
+    loop {
+        let snapshot = resolve_sources(sources)
+            .map(|paths| {
+                let mtimes: Vec<_> = paths
+                    .iter()
+                    .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+                    .collect();
+                (paths, mtimes)
+            })
+            .map_err(|e| e.to_string());
+        if last.as_ref() != Some(&snapshot) {
+            match &snapshot {
+                Ok((paths, _)) => match merge_cached(paths, sources, output, false) {
+                    Ok((cache_file, _)) => {
+                        println!("{}", cache_file.display());
+                        if let Some(command) = exec {
+                            run_watch_command(command, &cache_file);
+                        }
+                    }
+                    Err(e) => eprintln!("starship-multi-config: {e}"),
+                },
+                Err(e) => eprintln!("starship-multi-config: {e}"),
+            }
+            last = Some(snapshot);
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Run the `watch --exec` hook for a new merge, reporting rather than returning failures.
+fn run_watch_command(command: &str, cache_file: &Path) {
+    let status = Command::new("sh")
+        .args(["-c", command])
+        .env("STARSHIP_CONFIG", cache_file)
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("starship-multi-config: warning: `{command}` exited with {status}"),
+        Err(e) => eprintln!("starship-multi-config: warning: `{command}`: {e}"),
+    }
+}
+
+/// Print the presets that `starship preset --list` knows, for `--preset list`.
+pub fn list_presets(sources: &Sources) -> Result<(), Box<dyn std::error::Error>> {
+    let names = run_starship(&sources.starship()?, &["preset", "--list"])?;
+    print!("{}", String::from_utf8_lossy(&names));
+    for (name, _) in builtin::PRESETS {
+        println!("{}{name}", builtin::PREFIX);
+    }
+    Ok(())
+}
+
+pub fn lint(
+    sources: &Sources,
+    strict: bool,
+    keep_going: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let paths = resolve_sources(sources)?;
+    if keep_going {
+        let errors: Vec<String> = paths
+            .iter()
+            .filter_map(|path| parse_config(path).err())
+            .collect();
+        for e in &errors {
+            eprintln!("starship-multi-config: {e}");
+        }
+        match errors.len() {
+            0 => {}
+            1 => return Err("1 file failed to parse".into()),
+            n => return Err(format!("{n} files failed to parse").into()),
+        }
+    }
+    let merged = merge_files(&paths, sources.type_check, sources.merge_max_depth)?;
+
+    let unknown: Vec<&String> = merged
+        .keys()
+        .filter(|key| !known_keys::TOP_LEVEL_KEYS.contains(&key.as_str()))
+        .collect();
+    for key in &unknown {
+        match known_keys::closest(key) {
+            Some(known) => println!("unknown top-level key `{key}` (did you mean `{known}`?)"),
+            None => println!("unknown top-level key `{key}`"),
+        }
+    }
+
+    match unknown.len() {
+        0 => {
+            println!("No unknown keys found.");
+            Ok(())
+        }
+        _ if !strict => Ok(()),
+        1 => Err("1 unknown key found".into()),
+        n => Err(format!("{n} unknown keys found").into()),
+    }
+}
+
+/// Variables read directly rather than through an option, with their defaults.
+const ENV_ONLY: &[(&str, Option<&str>)] = &[
+    ("STARSHIP_MULTI_LOG", None),
+    ("STARSHIP_MULTI_CACHE_DIR", None),
+    ("STARSHIP_MULTI_UMASK", Some("077")),
+];
+
+pub fn list_env() -> Result<(), Box<dyn std::error::Error>> {
+    // Collect options from every subcommand, since some are only on one
+    let mut vars = BTreeMap::new();
+    let mut commands = vec![Cli::command()];
+    while let Some(command) = commands.pop() {
+        for arg in command.get_arguments() {
+            if let Some(name) = arg.get_env() {
+                let default = arg
+                    .get_default_values()
+                    .iter()
+                    .map(|v| v.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                vars.insert(name.to_string_lossy().into_owned(), Some(default));
+            }
+        }
+        commands.extend(command.get_subcommands().cloned());
+    }
+    for (name, default) in ENV_ONLY {
+        vars.insert(name.to_string(), default.map(str::to_string));
+    }
+
+    let width = vars.keys().map(String::len).max().unwrap_or(0);
+    for (name, default) in vars {
+        let value = match env::var_os(&name) {
+            Some(value) => value.to_string_lossy().into_owned(),
+            None => match default.filter(|d| !d.is_empty()) {
+                Some(default) => format!("(default: {default})"),
+                None => "(unset)".to_string(),
+            },
+        };
+        println!("{name:width$}  {value}");
+    }
+    Ok(())
+}
+
+pub fn doctor(
+    sources: &Sources,
+    output: &Output,
+    format: Format,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut problems = 0;
+
+    let starship = sources.starship();
+    problems += usize::from(starship.is_err());
+
+    let cache = cache_dir().map(|dir| {
+        let writable =
+            fs::create_dir_all(&dir).and_then(|()| tempfile::NamedTempFile::new_in(&dir).map(drop));
+        (dir, writable)
+    });
+    problems += usize::from(!matches!(cache, Ok((_, Ok(())))));
+
+    let mut vars: Vec<_> = env::vars_os()
+        .filter(|(k, _)| k.to_string_lossy().starts_with("STARSHIP_"))
+        .map(|(k, v)| {
+            (
+                k.to_string_lossy().into_owned(),
+                v.to_string_lossy().into_owned(),
+            )
+        })
+        .collect();
+    vars.sort();
+
+    let resolved = if sources.preset.is_empty()
+        && sources.configs.is_empty()
+        && sources.config_dir.is_none()
+        && sources.manifest.is_none()
+        && sources.force_config.is_none()
+    {
+        default_sources().map(|paths| {
+            paths
+                .into_iter()
+                .map(|path| (path, SourceKind::Config))
+                .collect()
+        })
+    } else {
+        resolve_source_kinds(sources)
+    };
+    problems += usize::from(!matches!(&resolved, Ok(paths) if !paths.is_empty()));
+
+    match format {
+        Format::Text => {
+            match &starship {
+                Ok(bin) => println!("starship binary: {}", bin.display()),
+                Err(e) => println!("starship binary: not found ({e})"),
+            }
+
+            match &cache {
+                Ok((dir, Ok(()))) => println!("cache directory: {} (writable)", dir.display()),
+                Ok((dir, Err(e))) => {
+                    println!("cache directory: {} (not writable: {e})", dir.display());
+                }
+                Err(e) => println!("cache directory: unavailable ({e})"),
+            }
+
+            println!("environment:");
+            if vars.is_empty() {
+                println!("  (no STARSHIP_* variables set)");
+            }
+            for (key, value) in &vars {
+                println!("  {key}={value}");
+            }
+
+            match &resolved {
+                Ok(paths) if paths.is_empty() => println!("sources: none found"),
+                Ok(paths) => {
+                    // Mirrors how a run would treat them, see `passes_through`
+                    match paths.as_slice() {
+                        [(path, _)] if passes_through(path, output) => {
+                            println!("sources: 1 file, passed through unmerged");
+                        }
+                        [_] => println!("sources: 1 file, merged into a cached config"),
+                        _ => println!(
+                            "sources: {} files, merged into a cached config",
+                            paths.len()
+                        ),
+                    }
+                    for (path, _) in paths {
+                        println!("  {}", path.display());
+                    }
+                }
+                Err(e) => println!("sources: failed to resolve ({e})"),
+            }
+
+            println!();
+            if problems == 0 {
+                println!("No problems found.");
+            }
+        }
+        Format::Json => {
+            let report = serde_json::json!({
+                "starship": match &starship {
+                    Ok(bin) => serde_json::json!({ "path": bin }),
+                    Err(e) => serde_json::json!({ "error": e.to_string() }),
+                },
+                "cache_dir": match &cache {
+                    Ok((dir, Ok(()))) => serde_json::json!({ "path": dir, "writable": true }),
+                    Ok((dir, Err(e))) => serde_json::json!({
+                        "path": dir,
+                        "writable": false,
+                        "error": e.to_string(),
+                    }),
+                    Err(e) => serde_json::json!({ "error": e.to_string() }),
+                },
+                "environment": vars
+                    .into_iter()
+                    .map(|(k, v)| (k, v.into()))
+                    .collect::<serde_json::Map<_, _>>(),
+                "sources": match &resolved {
+                    Ok(paths) => sources_json(paths),
+                    Err(e) => serde_json::json!({ "error": e.to_string() }),
+                },
+                "problems": problems,
+            });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+
+    match problems {
+        0 => Ok(()),
+        1 => Err("1 problem found".into()),
+        n => Err(format!("{n} problems found").into()),
+    }
+}
+
+/// `paths` as a JSON array of `{"path", "kind", "order"}` objects, numbered in order.
+fn sources_json(paths: &[(PathBuf, SourceKind)]) -> serde_json::Value {
+    paths
+        .iter()
+        .enumerate()
+        .map(|(order, (path, kind))| {
+            serde_json::json!({ "path": path, "kind": kind.name(), "order": order })
+        })
+        .collect()
+}
+
+pub fn migrate(
+    sources: &Sources,
+    target_dir: &Path,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let target_dir = &expand_path(target_dir);
+    let paths = resolve_sources(sources)?;
+
+    // Number in steps of 10 so files can later be slotted in between, padded so the
+    // names sort in order however many there are
+    let width = (paths.len() * 10).to_string().len().max(3);
+    let copies: Vec<(&PathBuf, PathBuf)> = paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let name = name.strip_suffix(".gz").unwrap_or(&name);
+            let name = [".json", ".ini", ".conf"]
+                .iter()
+                .find_map(|ext| name.strip_suffix(ext))
+                .unwrap_or(name);
+            let stem = name.strip_suffix(".toml").unwrap_or(name);
+            let target = target_dir.join(format!("{:0width$}-{stem}.toml", (i + 1) * 10));
+            (path, target)
+        })
+        .collect();
+
+    // Drop-ins from an earlier migration would merge along with the new ones, so they
+    // are replaced as a whole
+    let existing: Vec<PathBuf> = match fs::read_dir(target_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| is_numbered_drop_in(path))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    if let Some(path) = existing.first() {
+        if !force {
+            return Err(format!(
+                "{}: already exists (use --force to overwrite)",
+                path.display()
+            )
+            .into());
+        }
+        for path in &existing {
+            fs::remove_file(path).map_err(|e| path_err(path, e))?;
+        }
+    }
+
+    fs::create_dir_all(target_dir).map_err(|e| path_err(target_dir, e))?;
+    for (source, target) in &copies {
+        // Compressed sources are written out decompressed, and JSON and INI sources as TOML.
+        // Included files are already copied in front of the file that includes them.
+        let content = if !is_toml(source) {
+            toml::to_string(&parse_config(source)?)?
+        } else {
+            strip_includes(&read_config(source).map_err(|e| path_err(source, e))?)
+        };
+        fs::write(target, content).map_err(|e| path_err(target, e))?;
+    }
+
+    // Absolute, so the line works from any directory, and single-quoted for the shell
+    let target_dir = fs::canonicalize(target_dir).map_err(|e| path_err(target_dir, e))?;
+    println!(
+        "export STARSHIP_CONFIG_DIR='{}'",
+        target_dir.display().to_string().replace('\'', r"'\''")
+    );
+    Ok(())
+}
+
+/// Whether `path` is named like a drop-in `migrate` writes, e.g. `010-base.toml`.
+fn is_numbered_drop_in(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.ends_with(".toml")
+        && name
+            .split_once('-')
+            .is_some_and(|(n, _)| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+pub fn freeze(
+    sources: &Sources,
+    output: &Output,
+    output_file: &Path,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // `-` streams just the TOML, e.g. to pipe it to another machine
+    if output_file.as_os_str() == "-" {
+        let output = Output {
+            no_header: true,
+            ..output.clone()
+        };
+        let paths = resolve_sources(sources)?;
+        let content = render(&paths, sources, &output, false)?;
+        std::io::stdout().write_all(content.as_bytes())?;
+        return Ok(());
+    }
+
+    let output_file = &expand_path(output_file);
+    if !force && output_file.exists() {
+        return Err(format!(
+            "{}: already exists (use --force to overwrite)",
+            output_file.display()
+        )
+        .into());
+    }
+
+    let paths = resolve_sources(sources)?;
+    let content = render(&paths, sources, output, false)?;
+    fs::write(output_file, content).map_err(|e| path_err(output_file, e))?;
+
+    if output.verify {
+        verify(sources, output_file);
+    }
+    Ok(())
+}
+
+pub fn sync(
+    sources: &Sources,
+    output: &Output,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Like starship, take the first path if `STARSHIP_CONFIG` lists several
+    let target = match env::var_os("STARSHIP_CONFIG") {
+        Some(config) if !config.is_empty() => {
+            let first = env::split_paths(&config).next().unwrap_or_default();
+            expand_path(&first)
+        }
+        _ => dirs::home_dir()
+            .ok_or("could not determine home directory")?
+            .join(".config/starship.toml"),
+    };
+
+    // `STARSHIP_CONFIG` often points at an earlier merge, which must stay as cached
+    let canonical = fs::canonicalize(&target).unwrap_or_else(|_| target.clone());
+    let cache = cache_dir()
+        .ok()
+        .map(|dir| fs::canonicalize(&dir).unwrap_or(dir));
+    if cache.is_some_and(|dir| canonical.starts_with(dir)) {
+        return Err(format!(
+            "{}: is a merged config in the cache directory; set STARSHIP_CONFIG to the \
+             file to sync to",
+            target.display()
+        )
+        .into());
+    }
+
+    // Without arguments the default config is a source, and overwriting it would lose
+    // it and merge the rest into it again on every sync
+    let paths = resolve_sources(sources)?;
+    if paths.contains(&canonical) {
+        return Err(format!(
+            "{}: is one of the sources, so syncing would overwrite it; pass the sources \
+             to merge explicitly",
+            target.display()
+        )
+        .into());
+    }
+
+    if !force && target.exists() {
+        return Err(format!(
+            "{}: already exists (use --force to overwrite, keeping a .bak copy)",
+            target.display()
+        )
+        .into());
+    }
+    let content = render(&paths, sources, output, false)?;
+    if target.exists() {
+        let mut backup = target.clone().into_os_string();
+        backup.push(".bak");
+        fs::copy(&target, &backup).map_err(|e| path_err(Path::new(&backup), e))?;
+    }
+    if let Some(dir) = target.parent() {
+        fs::create_dir_all(dir).map_err(|e| path_err(dir, e))?;
+    }
+    fs::write(&target, content).map_err(|e| path_err(&target, e))?;
+
+    if output.verify {
+        verify(sources, &target);
+    }
+    Ok(())
+}
+
+pub fn apply(
+    sources: &Sources,
+    base: &Path,
+    backup: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let base = &expand_path(base);
+    // The result is written back as TOML, which only a plain TOML base can hold
+    if !is_toml(base) || base.extension().is_some_and(|ext| ext == "gz") {
+        return Err(path_err(base, "can only apply to a plain TOML file").into());
+    }
+    // Write through symlinks, like an editor would
+    let base = fs::canonicalize(base).map_err(|e| path_err(base, e))?;
+    let mut paths = vec![base.clone()];
+    paths.extend(resolve_sources(sources)?.into_iter().filter(|p| *p != base));
+    let merged = merge_files(&paths, sources.type_check, sources.merge_max_depth)?;
+
+    if backup {
+        let mut backup = base.clone().into_os_string();
+        backup.push(".bak");
+        fs::copy(&base, &backup).map_err(|e| path_err(Path::new(&backup), e))?;
+    }
+
+    // Replace the file atomically so an interrupted write can't lose the base
+    let dir = base.parent().unwrap_or(Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir).map_err(|e| path_err(dir, e))?;
+    let permissions = fs::metadata(&base)
+        .map_err(|e| path_err(&base, e))?
+        .permissions();
+    tmp.as_file().set_permissions(permissions)?;
+    tmp.write_all(toml::to_string(&merged)?.as_bytes())?;
+    tmp.persist(&base).map_err(|e| path_err(&base, e.error))?;
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! INI layers (`.ini` or `.conf`): `key = value` lines, grouped under `[section]`
+//! headers that become tables. A dotted header like `[palettes.mine]` nests.
+//!
+//! Values are read as booleans or numbers when they look like one, and as strings
+//! otherwise, with surrounding quotes removed. Lines starting with `;` or `#` are
+//! comments.
+
+/// Parse INI `content` into a table.
+pub fn parse(content: &str) -> Result<toml::Table, String> {
+    let mut table = toml::Table::new();
+    let mut section: Vec<&str> = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with([';', '#']) {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header
+                .strip_suffix(']')
+                .ok_or_else(|| format!("line {}: unterminated section header", i + 1))?;
+            section = header.split('.').map(str::trim).collect();
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected `key = value`", i + 1))?;
+
+        let mut target = &mut table;
+        for name in &section {
+            let entry = target
+                .entry(name.to_string())
+                .or_insert_with(|| toml::Table::new().into());
+            target = entry
+                .as_table_mut()
+                .ok_or_else(|| format!("line {}: `{name}` is already a value", i + 1))?;
+        }
+        target.insert(key.trim().to_string(), value_of(value.trim()));
+    }
+    Ok(table)
+}
+
+fn value_of(value: &str) -> toml::Value {
+    for quote in ['"', '\''] {
+        if let Some(s) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return s.into();
+        }
+    }
+    if let Ok(b) = value.parse::<bool>() {
+        b.into()
+    } else if let Ok(n) = value.parse::<i64>() {
+        n.into()
+    } else if let Ok(f) = value.parse::<f64>() {
+        f.into()
+    } else {
+        value.into()
+    }
+}
@@ -0,0 +1,145 @@
+//! Top-level keys that starship understands, for catching typos.
+
+/// Starship's top-level options and module sections.
+pub const TOP_LEVEL_KEYS: &[&str] = &[
+    // Prompt options
+    "$schema",
+    "add_newline",
+    "command_timeout",
+    "continuation_prompt",
+    "follow_symlinks",
+    "format",
+    "palette",
+    "palettes",
+    "profiles",
+    "right_format",
+    "scan_timeout",
+    // Modules
+    "aws",
+    "azure",
+    "battery",
+    "buf",
+    "bun",
+    "c",
+    "character",
+    "cmake",
+    "cmd_duration",
+    "cobol",
+    "conda",
+    "container",
+    "cpp",
+    "crystal",
+    "custom",
+    "daml",
+    "dart",
+    "deno",
+    "directory",
+    "direnv",
+    "docker_context",
+    "dotnet",
+    "elixir",
+    "elm",
+    "env_var",
+    "erlang",
+    "fennel",
+    "fill",
+    "fortran",
+    "fossil_branch",
+    "fossil_metrics",
+    "gcloud",
+    "git_branch",
+    "git_commit",
+    "git_metrics",
+    "git_state",
+    "git_status",
+    "gleam",
+    "golang",
+    "gradle",
+    "guix_shell",
+    "haskell",
+    "haxe",
+    "helm",
+    "hg_branch",
+    "hg_state",
+    "hostname",
+    "java",
+    "jobs",
+    "julia",
+    "kotlin",
+    "kubernetes",
+    "line_break",
+    "localip",
+    "lua",
+    "memory_usage",
+    "meson",
+    "mise",
+    "mojo",
+    "nats",
+    "netns",
+    "nim",
+    "nix_shell",
+    "nodejs",
+    "ocaml",
+    "odin",
+    "opa",
+    "openstack",
+    "os",
+    "package",
+    "perl",
+    "php",
+    "pijul_channel",
+    "pixi",
+    "pulumi",
+    "purescript",
+    "python",
+    "quarto",
+    "raku",
+    "red",
+    "rlang",
+    "ruby",
+    "rust",
+    "scala",
+    "shell",
+    "shlvl",
+    "singularity",
+    "solidity",
+    "spack",
+    "status",
+    "sudo",
+    "swift",
+    "terraform",
+    "time",
+    "typst",
+    "username",
+    "vagrant",
+    "vcsh",
+    "vlang",
+    "xmake",
+    "zig",
+];
+
+/// The known key most similar to `key`, if it's close enough to be a likely typo.
+pub fn closest(key: &str) -> Option<&'static str> {
+    TOP_LEVEL_KEYS
+        .iter()
+        .map(|known| (edit_distance(key, known), *known))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known)| known)
+}
+
+/// Levenshtein distance between two strings, by characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev + usize::from(ca != *cb);
+            prev = row[j + 1];
+            row[j + 1] = substitute.min(prev + 1).min(row[j] + 1);
+        }
+    }
+    row[b.len()]
+}
@@ -0,0 +1,105 @@
+//! Incremental parsing: a sidecar file next to the merged configs records each
+//! layer's parsed table, so a cold merge only re-reads the files that changed.
+
+use std::{
+    fs,
+    hash::Hash,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    cache::{cache_dir, hash_key, write_cache},
+    merge::parse_config,
+    path_err,
+};
+
+/// Parse `paths` like [`parse_config`], reusing tables from the sidecar for files whose
+/// mtime hasn't changed since it was written, then update the sidecar.
+pub fn parse_incremental(
+    paths: &[PathBuf],
+) -> Result<Vec<toml::Table>, Box<dyn std::error::Error>> {
+    // Keyed on the paths alone (unlike the merged config) so edits find the old layers
+    let hash = hash_key(|h| {
+        paths.hash(h);
+        Ok(())
+    })?;
+    let sidecar = cache_dir()?.join(format!("layers-{hash}.toml"));
+    let mut cached = load_sidecar(&sidecar);
+
+    let mut tables = Vec::with_capacity(paths.len());
+    let mut layers = toml::value::Array::with_capacity(paths.len());
+    for path in paths {
+        let mtime = mtime_key(path)?;
+        let reused = cached
+            .iter_mut()
+            .find(|(p, m, _)| p == path && *m == mtime)
+            .map(|(_, _, table)| std::mem::take(table));
+        let table = match reused {
+            Some(table) => {
+                log::trace!("reusing parsed {}", path.display());
+                table
+            }
+            None => parse_config(path)?,
+        };
+
+        let mut layer = toml::Table::new();
+        layer.insert("path".into(), path.display().to_string().into());
+        layer.insert("mtime".into(), mtime.into());
+        layer.insert("config".into(), table.clone().into());
+        layers.push(layer.into());
+        tables.push(table);
+    }
+
+    let mut content = toml::Table::new();
+    content.insert("layer".into(), layers.into());
+    // The sidecar only saves work, so failing to write it isn't an error
+    if let Err(e) = write_cache(&sidecar, toml::to_string(&content)?.as_bytes()) {
+        log::debug!("could not write {}: {e}", sidecar.display());
+    }
+    Ok(tables)
+}
+
+/// Read the `(path, mtime, table)` entries of a sidecar, or nothing if it's missing or
+/// unreadable.
+fn load_sidecar(sidecar: &Path) -> Vec<(PathBuf, String, toml::Table)> {
+    let Ok(content) = fs::read_to_string(sidecar) else {
+        log::debug!("no layer sidecar at {}", sidecar.display());
+        return Vec::new();
+    };
+    let Ok(mut content) = content.parse::<toml::Table>() else {
+        log::debug!("ignoring corrupt layer sidecar {}", sidecar.display());
+        return Vec::new();
+    };
+
+    let Some(toml::Value::Array(layers)) = content.remove("layer") else {
+        return Vec::new();
+    };
+    layers
+        .into_iter()
+        .filter_map(|layer| {
+            let toml::Value::Table(mut layer) = layer else {
+                return None;
+            };
+            let path = layer.get("path")?.as_str()?.into();
+            let mtime = layer.get("mtime")?.as_str()?.to_string();
+            let toml::Value::Table(config) = layer.remove("config")? else {
+                return None;
+            };
+            Some((path, mtime, config))
+        })
+        .collect()
+}
+
+/// A file's mtime with full precision, as stored in the sidecar.
+fn mtime_key(path: &Path) -> Result<String, String> {
+    let mtime = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(|e| path_err(path, e))?;
+    let since_epoch = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok(format!(
+        "{}.{:09}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    ))
+}
@@ -0,0 +1,40 @@
+//! Minimal stderr logger controlled by `STARSHIP_MULTI_LOG`.
+//!
+//! This runs on every prompt, so nothing is installed unless the variable is set; the
+//! `log` macros then short-circuit on the default `Off` max level.
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "starship-multi-config: {}: {}",
+                record.level().as_str().to_lowercase(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger if `STARSHIP_MULTI_LOG` names a level (`error`, `warn`, `info`,
+/// `debug`, or `trace`).
+pub fn init() {
+    let Some(level) = std::env::var_os("STARSHIP_MULTI_LOG") else {
+        return;
+    };
+    let Some(filter) = level.to_str().and_then(|l| l.parse::<LevelFilter>().ok()) else {
+        return;
+    };
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(filter);
+    }
+}
@@ -1,12 +1,46 @@
 use std::{
-    collections::hash_map::DefaultHasher,
+    env,
+    ffi::OsString,
     fs,
-    hash::{Hash, Hasher},
+    hash::Hash,
+    io::Write,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use clap::Parser;
+mod builtin;
+mod cache;
+mod commands;
+mod ini;
+mod known_keys;
+mod layers;
+mod logger;
+mod manifest;
+mod merge;
+mod paths;
+mod remote;
+mod sources;
+mod trace;
+mod when;
+
+use clap::{Args, Parser, Subcommand, ValueEnum, builder::BoolishValueParser};
+
+use crate::{
+    cache::{hash_key, usable_cache_dir, write_cache, write_temp},
+    commands::{
+        apply, bench, doctor, edit, explain, fingerprint, freeze, lint, list_env, list_presets,
+        migrate, report, schema, sync, watch,
+    },
+    layers::parse_incremental,
+    merge::{
+        is_plain, is_toml, merge_tables, normalize_newlines, parse_assignment, parse_config,
+        parse_files, prune_palettes,
+    },
+    paths::expand_path,
+    sources::resolve_sources,
+};
 
 /// Merge multiple Starship TOML configs and print the path to the merged file.
 ///
@@ -14,19 +48,487 @@ use clap::Parser;
 ///   export STARSHIP_CONFIG="$(starship-multi-config base.toml overrides.toml)"
 ///   eval "$(starship init zsh)"
 #[derive(Parser)]
-#[command(version)]
+#[command(
+    name = "starship-multi-config",
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
-    /// Use Starship presets as base config layers (repeatable, left-to-right).
-    /// Runs `starship preset <NAME>` to fetch each preset's TOML.
-    #[arg(long)]
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    sources: Sources,
+
+    #[command(flatten)]
+    output: Output,
+}
+
+/// Options controlling the cached merged config file.
+#[derive(Args, Clone)]
+struct Output {
+    /// Don't prepend a comment listing the source files and generation time to the
+    /// merged config.
+    #[arg(long, env = "STARSHIP_MULTI_NO_HEADER", value_parser = BoolishValueParser::new())]
+    no_header: bool,
+
+    /// Formatting of the merged config file.
+    #[arg(long, env = "STARSHIP_MULTI_TOML_STYLE", value_enum, default_value_t = TomlStyle::Compact)]
+    toml_style: TomlStyle,
+
+    /// Command that receives the merged TOML on stdin and prints the final TOML to
+    /// stdout, e.g. to apply a theme.
+    #[arg(long, env = "STARSHIP_MULTI_POSTPROCESS", value_name = "COMMAND")]
+    postprocess: Option<PathBuf>,
+
+    /// Environment variables whose values affect the merge, so changing them invalidates
+    /// the cache (comma-separated).
+    #[arg(
+        long,
+        env = "STARSHIP_MULTI_CACHE_ENV",
+        value_name = "VARS",
+        value_delimiter = ','
+    )]
+    cache_env: Vec<String>,
+
+    /// After a fresh merge, check the result with `starship print-config` and warn
+    /// if starship rejects it.
+    #[arg(long, env = "STARSHIP_MULTI_VERIFY", value_parser = BoolishValueParser::new())]
+    verify: bool,
+
+    /// Append a line per run to `trace.log` in the cache directory, with the time, the
+    /// number of sources, whether the cache was hit, the time taken, and the printed path.
+    #[arg(long, env = "STARSHIP_MULTI_TRACE", value_parser = BoolishValueParser::new())]
+    trace: bool,
+
+    /// Warn when the merged config is larger than this many bytes, which usually means
+    /// a glob pulled in a file that isn't a starship config.
+    #[arg(long, env = "STARSHIP_MULTI_MAX_OUTPUT", value_name = "BYTES", default_value_t = 1 << 20)]
+    max_output: usize,
+
+    /// Fail instead of writing a merged config larger than this many bytes.
+    #[arg(long, env = "STARSHIP_MULTI_MAX_OUTPUT_HARD", value_name = "BYTES", default_value_t = 16 << 20)]
+    max_output_hard: usize,
+
+    /// Always write the merged config to this file, atomically, instead of a
+    /// content-addressed file in the cache directory, for tools that expect a fixed
+    /// path. It's re-merged whenever the sources change.
+    #[arg(long = "output", env = "STARSHIP_MULTI_OUTPUT", value_name = "FILE")]
+    fixed_output: Option<PathBuf>,
+
+    /// Drop `[palettes.*]` tables other than the one the merged `palette` key selects,
+    /// e.g. a preset's palette after a layer switched to its own.
+    #[arg(long, env = "STARSHIP_MULTI_PRUNE_PALETTES", value_parser = BoolishValueParser::new())]
+    prune_palettes: bool,
+
+    /// Set a dotted key in the merged config, over every layer (repeatable). The value
+    /// is TOML, so strings need quotes, e.g. `--set 'character.success_symbol="[>](green)"'`.
+    #[arg(long, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+
+    /// Experimental: keep each source's parsed table in a sidecar file, so a merge
+    /// after an edit only re-reads the files whose mtime changed.
+    #[arg(long, env = "STARSHIP_MULTI_INCREMENTAL", value_parser = BoolishValueParser::new())]
+    incremental: bool,
+}
+
+#[derive(Clone, Copy, Hash, ValueEnum)]
+enum TomlStyle {
+    /// `toml`'s default output
+    Compact,
+    /// Expanded arrays, one element per line
+    Pretty,
+}
+
+impl TomlStyle {
+    fn serialize(self, table: &toml::Table) -> Result<String, toml::ser::Error> {
+        match self {
+            TomlStyle::Compact => toml::to_string(table),
+            TomlStyle::Pretty => toml::to_string_pretty(table),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum GlobSort {
+    /// File names, lexically or with `--glob-numeric-sort`
+    Name,
+    /// Modification time, oldest first, with ties broken by name
+    Mtime,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    /// Human-readable text
+    Text,
+    /// JSON, for scripts
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Seed {
+    /// Start from an empty config
+    Empty,
+    /// Start from starship's built-in defaults (`starship print-config --default`)
+    Default,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum PresetCacheKey {
+    /// The preset name plus the starship binary's path and mtime
+    Binary,
+    /// Only the preset name, so a re-installed binary reuses cached presets
+    Name,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Print a stable hash of the merged config contents.
+    ///
+    /// Unlike the cache key, this depends only on the merged result, not on source
+    /// paths or mtimes, so equivalent configs fingerprint the same across machines.
+    Fingerprint {
+        #[command(flatten)]
+        sources: Sources,
+    },
+
+    /// Print the merged config annotated with the files that set each top-level key.
+    ///
+    /// With `--format json`, print the sources in merge order and the files that set
+    /// each top-level key instead.
+    Explain {
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+
+        #[command(flatten)]
+        sources: Sources,
+    },
+
+    /// Print statistics about the merge: how many keys the merged config has, how many
+    /// were overridden or deleted along the way, and how many each source set.
+    Report {
+        #[command(flatten)]
+        sources: Sources,
+    },
+
+    /// Print a JSON Schema describing the keys and value types in the merged config.
+    ///
+    /// This isn't starship's schema but one derived from your config, e.g. to catch
+    /// type drift between machines. Arrays take their item type from the first element.
+    Schema {
+        #[command(flatten)]
+        sources: Sources,
+    },
+
+    /// Open the last config file in `$VISUAL` or `$EDITOR`, then re-merge and print the
+    /// merged config's path like the default command.
+    Edit {
+        #[command(flatten)]
+        sources: Sources,
+
+        #[command(flatten)]
+        output: Output,
+    },
+
+    /// Re-merge whenever a source changes, printing the merged config's path each time.
+    ///
+    /// Stops on Ctrl-C. A merge that fails, e.g. on a half-saved file, is reported and
+    /// retried on the next change.
+    Watch {
+        /// How often to check the sources for changes.
+        #[arg(long, value_name = "DURATION", default_value = "500ms", value_parser = humantime::parse_duration)]
+        interval: Duration,
+
+        /// Shell command to run after each merge, with `STARSHIP_CONFIG` set to the
+        /// merged config, e.g. to reload the prompt. Failures are reported but don't
+        /// stop watching.
+        #[arg(long, env = "STARSHIP_MULTI_WATCH_CMD", value_name = "COMMAND")]
+        exec: Option<String>,
+
+        #[command(flatten)]
+        sources: Sources,
+
+        #[command(flatten)]
+        output: Output,
+    },
+
+    /// Warn about top-level keys in the merged config that starship doesn't know,
+    /// which are usually typos like `[git_branche]`.
+    Lint {
+        /// Exit with status 1 if any unknown keys are found.
+        #[arg(long)]
+        strict: bool,
+
+        /// Report every config file that fails to parse, instead of stopping at the
+        /// first.
+        #[arg(long)]
+        keep_going: bool,
+
+        #[command(flatten)]
+        sources: Sources,
+    },
+
+    /// Check the environment and report how configs would be resolved.
+    ///
+    /// Exits with status 1 if a problem is found.
+    Doctor {
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+
+        #[command(flatten)]
+        sources: Sources,
+
+        #[command(flatten)]
+        output: Output,
+    },
+
+    /// List every environment variable this tool reads, with its current value or its
+    /// default if unset.
+    Env,
+
+    /// Copy the sources into a directory as numbered drop-ins that merge in the same
+    /// order (`010-base.toml`, `020-overrides.toml`, ...), and print the
+    /// `STARSHIP_CONFIG_DIR` to set.
+    Migrate {
+        /// Directory to copy the sources into.
+        target_dir: PathBuf,
+
+        /// Replace numbered drop-ins already in the target directory.
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        sources: Sources,
+    },
+
+    /// Write the merged config to a standalone file, e.g. to copy to a machine without
+    /// starship-multi-config.
+    Freeze {
+        /// File to write the merged config to, or `-` to print it to stdout without
+        /// the header.
+        output_file: PathBuf,
+
+        /// Overwrite the output file if it exists.
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        sources: Sources,
+
+        #[command(flatten)]
+        output: Output,
+    },
+
+    /// Write the merged config to where starship looks for it by default: the first
+    /// path in `$STARSHIP_CONFIG`, or `~/.config/starship.toml`. Plain starship then
+    /// picks it up without this tool in the loop.
+    Sync {
+        /// Overwrite an existing config, keeping a copy as `<FILE>.bak`.
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        sources: Sources,
+
+        #[command(flatten)]
+        output: Output,
+    },
+
+    /// Merge the sources on top of a base file and write the result back into it,
+    /// flattening the layers into one canonical file. Comments in the base are lost.
+    Apply {
+        /// File to merge into.
+        base: PathBuf,
+
+        /// Don't save the previous contents to `<BASE>.bak` first.
+        #[arg(long)]
+        no_backup: bool,
+
+        #[command(flatten)]
+        sources: Sources,
+    },
+
+    /// Time a cold merge and a warm cache hit for the given sources.
+    Bench {
+        #[command(flatten)]
+        sources: Sources,
+
+        #[command(flatten)]
+        output: Output,
+    },
+}
+
+#[derive(Args)]
+struct Sources {
+    /// Use Starship presets as base config layers (repeatable or comma-separated,
+    /// left-to-right). Runs `starship preset <NAME>` to fetch each preset's TOML. A value
+    /// containing `/` or ending in `.toml` is read as a local preset file instead.
+    /// `builtin:<NAME>` selects one of the presets compiled into this binary without
+    /// running starship. `list` prints the available presets instead of merging.
+    #[arg(long, env = "STARSHIP_PRESET", value_delimiter = ',')]
     preset: Vec<String>,
 
+    /// Always re-run `starship preset` instead of reusing cached preset output.
+    /// The cache is still refreshed.
+    #[arg(long, env = "STARSHIP_PRESET_NO_CACHE", value_parser = BoolishValueParser::new())]
+    preset_no_cache: bool,
+
+    /// Include `starship --version` in the preset cache key, so upgrading starship
+    /// always refreshes presets even if the binary's path and mtime are unchanged.
+    #[arg(long, env = "STARSHIP_PRESET_VERSION", value_parser = BoolishValueParser::new())]
+    preset_version: bool,
+
+    /// Record starship's version with the cached presets, and delete all of them when
+    /// it changes, so an upgrade regenerates every preset.
+    #[arg(long, env = "STARSHIP_PRESET_AUTO_REFRESH", value_parser = BoolishValueParser::new())]
+    preset_auto_refresh: bool,
+
+    /// What cached preset output is keyed on. `name` skips regenerating presets when
+    /// the binary is re-fetched, e.g. on CI, but keeps stale presets after an upgrade
+    /// until the cache directory is cleared.
+    #[arg(long, env = "STARSHIP_PRESET_CACHE_KEY", value_enum, default_value_t = PresetCacheKey::Binary)]
+    preset_cache_key: PresetCacheKey,
+
     /// TOML config files to merge (left-to-right, later files override).
-    #[arg(required_unless_present = "preset")]
+    /// `https://` URLs are fetched and cached (see `--remote-ttl`), as are files in git
+    /// repositories given as `git+<repo>#<path>[@<ref>]`.
+    /// Files ending in `.json` are read as JSON, where `null` deletes a key, and files
+    /// ending in `.ini` or `.conf` as INI, with sections as tables. Files
+    /// ending in `.gz` are decompressed before parsing. Use `-` to read a
+    /// layer from stdin. Drop-ins in a sibling `<CONFIG>.d/` directory are merged
+    /// right after their config. Without configs or presets, defaults to
+    /// `~/.config/starship.toml` plus any drop-ins in `~/.config/starship.d/`.
+    ///
+    /// A directory stands for the `*.toml` files directly inside it, sorted by name.
+    ///
+    /// Prefix a config with `env:NAME=VALUE?` to only include it when the
+    /// environment variable `NAME` equals `VALUE` (repeat to require several).
+    ///
+    /// Suffix a config with `#KEY` (repeatable) to merge only those top-level keys of
+    /// it, e.g. `big.toml#git_status`.
+    ///
+    /// Quoted glob patterns, including brace groups like `{base,theme}/*.toml`, are
+    /// expanded in order with each alternative's matches sorted by name.
     configs: Vec<PathBuf>,
+
+    /// Sort glob matches by the numbers in their file names, so `2-x.toml` comes before
+    /// `10-x.toml`, instead of lexically.
+    #[arg(long, env = "STARSHIP_GLOB_NUMERIC_SORT", value_parser = BoolishValueParser::new())]
+    glob_numeric_sort: bool,
+
+    /// What glob matches are sorted by. `mtime` puts the most recently modified file
+    /// last, so the newest of a set of snapshots wins.
+    #[arg(long, env = "STARSHIP_GLOB_SORT", value_enum, default_value_t = GlobSort::Name)]
+    glob_sort: GlobSort,
+
+    /// Skip config files that aren't valid UTF-8, e.g. binary files matched by a broad
+    /// glob, with a warning instead of failing. Each file is read on every run to check.
+    #[arg(long, env = "STARSHIP_MULTI_SKIP_UNREADABLE", value_parser = BoolishValueParser::new())]
+    skip_unreadable: bool,
+
+    /// Fail unless the first config resolves to a file with settings in it, so that a
+    /// mandatory base layer is never silently dropped, e.g. by a glob matching nothing.
+    #[arg(long, env = "STARSHIP_MULTI_REQUIRE_FIRST", value_parser = BoolishValueParser::new())]
+    require_first: bool,
+
+    /// Never write to the cache directory: merge from scratch on every run into a new
+    /// temporary file, and run `starship preset` fresh each time.
+    #[arg(long, env = "STARSHIP_MULTI_READONLY", value_parser = BoolishValueParser::new())]
+    readonly: bool,
+
+    /// Maximum number of config files to merge, as a guard against runaway globs.
+    #[arg(
+        long,
+        env = "STARSHIP_MULTI_CONFIG_MAX",
+        value_name = "N",
+        default_value_t = 256
+    )]
+    config_max: usize,
+
+    /// How many tables deep to merge before replacing deeper tables wholesale, with a
+    /// warning. Guards against stack exhaustion on pathological generated configs.
+    #[arg(
+        long,
+        env = "STARSHIP_MERGE_MAX_DEPTH",
+        value_name = "N",
+        default_value_t = merge::DEFAULT_MAX_DEPTH
+    )]
+    merge_max_depth: usize,
+
+    /// Directories of `*.toml` drop-ins to merge after the presets and before the
+    /// configs, as a list separated like `PATH`. Each directory's files are merged in
+    /// name order, and later directories override earlier ones.
+    #[arg(long, env = "STARSHIP_CONFIG_DIR", value_name = "DIRS")]
+    config_dir: Option<OsString>,
+
+    /// Base directory for relative config paths, instead of the working directory.
+    /// Absolute and `~`-prefixed paths are unaffected.
+    #[arg(long, env = "STARSHIP_CONFIG_HOME", value_name = "DIR")]
+    config_home: Option<PathBuf>,
+
+    /// TOML manifest listing `presets` and `layers` (config arguments, globs allowed)
+    /// to merge before the ones given on the command line. Relative layers resolve
+    /// against the manifest's directory.
+    #[arg(long, env = "STARSHIP_CONFIG_FILE", value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// How long to reuse a fetched `https://` config or preset before fetching it
+    /// again. If a fetch fails, the previous copy is used regardless.
+    #[arg(long, env = "STARSHIP_REMOTE_TTL", value_name = "DURATION", default_value = "1h", value_parser = humantime::parse_duration)]
+    remote_ttl: Duration,
+
+    /// Profile from the manifest's `[profiles.<NAME>]` tables whose presets and layers
+    /// to merge after the manifest's top-level ones.
+    #[arg(long, env = "STARSHIP_PROFILE", value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Use only this config file, ignoring all presets, configs, and other sources.
+    /// Meant as an escape hatch while debugging a layered setup.
+    #[arg(long, env = "STARSHIP_MULTI_FORCE_CONFIG", value_name = "FILE")]
+    force_config: Option<PathBuf>,
+
+    /// Base layer underneath all presets and configs.
+    #[arg(long, env = "STARSHIP_MERGE_SEED", value_enum, default_value_t = Seed::Empty)]
+    seed: Seed,
+
+    /// Warn when a layer replaces a table with a non-table value or vice versa.
+    #[arg(long, env = "STARSHIP_MERGE_TYPE_CHECK", value_parser = BoolishValueParser::new())]
+    type_check: bool,
+
+    /// Starship binary to run for presets and checks, instead of `starship` on the
+    /// `PATH`.
+    #[arg(long, env = "STARSHIP", value_name = "PATH")]
+    starship_bin: Option<PathBuf>,
+}
+
+impl Sources {
+    /// Locate the starship binary, honoring `--starship-bin`. It's an error if that's this
+    /// binary, e.g. when it's installed as `starship`, since it would run itself.
+    fn starship(&self) -> Result<PathBuf, String> {
+        let name = self
+            .starship_bin
+            .as_deref()
+            .map_or_else(|| PathBuf::from("starship"), expand_path);
+        let bin = which::which(&name).map_err(|e| path_err(&name, e))?;
+
+        let is_self = env::current_exe()
+            .and_then(fs::canonicalize)
+            .is_ok_and(|exe| fs::canonicalize(&bin).is_ok_and(|bin| bin == exe));
+        if is_self {
+            return Err(path_err(
+                &bin,
+                "is starship-multi-config itself, not starship; set STARSHIP to the real \
+                 starship binary",
+            ));
+        }
+        Ok(bin)
+    }
 }
 
 fn main() {
+    logger::init();
+
     if let Err(e) = run() {
         eprintln!("starship-multi-config: {e}");
         std::process::exit(1);
@@ -36,182 +538,380 @@ fn main() {
 fn run() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
-    // Resolve preset configs if --preset is set
-    let mut paths: Vec<PathBuf> = Vec::new();
-    if !cli.preset.is_empty() {
-        let bin = which::which("starship").map_err(|e| format!("starship: {e}"))?;
-        for name in &cli.preset {
-            paths.push(resolve_preset(&bin, name)?);
-        }
+    match cli.command {
+        Some(Commands::Fingerprint { sources }) => fingerprint(&sources),
+        Some(Commands::Explain { format, sources }) => explain(&sources, format),
+        Some(Commands::Schema { sources }) => schema(&sources),
+        Some(Commands::Report { sources }) => report(&sources),
+        Some(Commands::Lint {
+            strict,
+            keep_going,
+            sources,
+        }) => lint(&sources, strict, keep_going),
+        Some(Commands::Edit { sources, output }) => edit(&sources, &output),
+        Some(Commands::Watch {
+            interval,
+            exec,
+            sources,
+            output,
+        }) => watch(&sources, &output, interval, exec.as_deref()),
+        Some(Commands::Doctor {
+            format,
+            sources,
+            output,
+        }) => doctor(&sources, &output, format),
+        Some(Commands::Env) => list_env(),
+        Some(Commands::Migrate {
+            target_dir,
+            force,
+            sources,
+        }) => migrate(&sources, &target_dir, force),
+        Some(Commands::Freeze {
+            output_file,
+            force,
+            sources,
+            output,
+        }) => freeze(&sources, &output, &output_file, force),
+        Some(Commands::Sync {
+            force,
+            sources,
+            output,
+        }) => sync(&sources, &output, force),
+        Some(Commands::Apply {
+            base,
+            no_backup,
+            sources,
+        }) => apply(&sources, &base, !no_backup),
+        Some(Commands::Bench { sources, output }) => bench(&sources, &output),
+        None => print_merged(&cli.sources, &cli.output),
     }
+}
 
-    paths.extend(cli.configs);
-
-    if paths.is_empty() {
-        return Err("no config files specified".into());
+fn print_merged(sources: &Sources, output: &Output) -> Result<(), Box<dyn std::error::Error>> {
+    if sources.preset.iter().any(|name| name == "list") {
+        return list_presets(sources);
     }
 
-    if paths.len() == 1 {
+    let start = Instant::now();
+    let paths = resolve_sources(sources)?;
+
+    if paths.len() == 1 && passes_through(&paths[0], output) {
         // Single source: print its path directly
+        log::info!("single source, passing through {}", paths[0].display());
         println!("{}", paths[0].display());
+        if output.trace {
+            trace::record(sources, &paths, "passthrough", start.elapsed(), &paths[0]);
+        }
         return Ok(());
     }
 
-    // Hash paths + mtimes to derive a cache key that invalidates when any source changes
-    let hash = hash_key(|h| {
-        for p in &paths {
+    let (cache_file, hit) = merge_cached(&paths, sources, output, false)?;
+    log::info!("merged config: {}", cache_file.display());
+    println!("{}", cache_file.display());
+    if output.trace {
+        let cache = if hit { "hit" } else { "miss" };
+        trace::record(sources, &paths, cache, start.elapsed(), &cache_file);
+    }
+    Ok(())
+}
+
+/// Whether a single source can be handed to starship as is, rather than merged into
+/// the cache like several sources would be.
+fn passes_through(path: &Path, output: &Output) -> bool {
+    // Starship can't read compressed files, and other formats have to be converted
+    output.fixed_output.is_none()
+        && output.set.is_empty()
+        && output.postprocess.is_none()
+        && !output.prune_palettes
+        && is_toml(path)
+        && path.extension().is_none_or(|ext| ext != "gz")
+        // Reserved keys and directives take effect, and are dropped, only when merging.
+        // A file that fails to parse is merged too, to report the error.
+        && parse_config(path).is_ok_and(|table| is_plain(&table))
+}
+
+/// Merge `paths` into a cache file and return its path, and whether it was a cache hit.
+/// Unless `force` is set, an existing cache file for the same sources is reused.
+/// Without a usable cache directory the merge goes to a new temp file instead.
+fn merge_cached(
+    paths: &[PathBuf],
+    sources: &Sources,
+    output: &Output,
+    force: bool,
+) -> Result<(PathBuf, bool), Box<dyn std::error::Error>> {
+    // A fixed output path can't be content-addressed, so a sidecar stamp records the
+    // cache key it was merged for
+    let fixed = output.fixed_output.as_deref().map(expand_path);
+    let (cache_file, stamp) = if let Some(file) = &fixed {
+        let stamp = (
+            file.with_added_extension("stamp"),
+            cache_key(paths, sources, output)?,
+        );
+        (Some(file.clone()), Some(stamp))
+    } else if let Some(dir) = usable_cache_dir(sources.readonly) {
+        let hash = cache_key(paths, sources, output)?;
+        (Some(dir.join(format!("{hash}.toml"))), None)
+    } else {
+        (None, None)
+    };
+    let stamped = match &stamp {
+        Some((stamp, key)) => fs::read_to_string(stamp).is_ok_and(|s| s == *key),
+        None => true,
+    };
+
+    // Re-merge only if no cached file exists for this paths+mtimes combination
+    if let Some(cache_file) = &cache_file
+        && !force
+        && stamped
+        && cache_file.exists()
+    {
+        log::debug!("cache hit: {}", cache_file.display());
+        return Ok((cache_file.clone(), true));
+    }
+
+    match &cache_file {
+        Some(cache_file) => log::debug!("cache miss: {}", cache_file.display()),
+        None => log::debug!("no cache, merging into a temporary file"),
+    }
+    let content = render(
+        paths,
+        sources,
+        output,
+        cache_file.is_some() && !sources.readonly,
+    )?;
+    let cache_file = match cache_file {
+        Some(cache_file) => write_cache(&cache_file, content.as_bytes())?,
+        None => write_temp(content.as_bytes())?,
+    };
+    // Unless the write fell back to a temp file
+    if let Some((stamp, key)) = &stamp
+        && fixed.as_ref() == Some(&cache_file)
+        && let Err(e) = fs::write(stamp, key)
+    {
+        eprintln!("starship-multi-config: warning: {}", path_err(stamp, e));
+    }
+
+    if output.verify {
+        verify(sources, &cache_file);
+    }
+    Ok((cache_file, false))
+}
+
+/// Key for the merged config of `paths`, which changes whenever any source or an option
+/// affecting the output does.
+fn cache_key(
+    paths: &[PathBuf],
+    sources: &Sources,
+    output: &Output,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // Hash paths + mtimes to derive a cache key that invalidates when any source
+    // changes. Paths are canonical and `fs::metadata` follows symlinks, so a symlinked
+    // source is keyed on its target: editing or retargeting the link's target
+    // invalidates the cache.
+    hash_key(|h| {
+        for p in paths {
             p.hash(h);
             let mtime = fs::metadata(p)
                 .and_then(|m| m.modified())
                 .map_err(|e| path_err(p, e))?;
             mtime.hash(h);
         }
+        for name in &output.cache_env {
+            name.hash(h);
+            env::var_os(name).hash(h);
+        }
+        // Different profiles resolve to different paths anyway, but keep them apart
+        // even if they happen to list the same files
+        sources.profile.hash(h);
+        sources.merge_max_depth.hash(h);
+        output.no_header.hash(h);
+        output.toml_style.hash(h);
+        output.prune_palettes.hash(h);
+        output.set.hash(h);
+        if let Some(command) = &output.postprocess {
+            let command = expand_path(command);
+            command.hash(h);
+            // Also invalidate when the postprocessor itself is edited
+            let mtime = which::which(&command)
+                .ok()
+                .and_then(|bin| fs::metadata(bin).and_then(|m| m.modified()).ok());
+            mtime.hash(h);
+        }
         Ok(())
-    })?;
-
-    let cache_file = cache_dir()?.join(format!("{hash}.toml"));
+    })
+}
 
-    // Re-merge only if no cached file exists for this paths+mtimes combination
-    if !cache_file.exists() {
-        let mut merged = toml::Table::new();
-        for path in &paths {
-            let content = fs::read_to_string(path).map_err(|e| path_err(path, e))?;
-            let table = content
-                .parse::<toml::Table>()
-                .map_err(|e| path_err(path, e))?;
-            merge(&mut merged, &table);
-        }
+/// Merge `paths` and produce the final file content, with the header and postprocessing
+/// that `output` asks for. Incremental parsing is only used if `can_cache`.
+fn render(
+    paths: &[PathBuf],
+    sources: &Sources,
+    output: &Output,
+    can_cache: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let tables = if output.incremental && can_cache {
+        parse_incremental(paths)?
+    } else {
+        parse_files(paths)?
+    };
+    let overrides = output
+        .set
+        .iter()
+        .map(|assignment| {
+            parse_assignment(assignment).map_err(|e| format!("--set {assignment}: {e}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut merged = merge_tables(
+        paths,
+        tables,
+        &overrides,
+        sources.type_check,
+        sources.merge_max_depth,
+    );
+    if output.prune_palettes {
+        prune_palettes(&mut merged);
+    }
+    let mut body = output.toml_style.serialize(&merged)?;
+    if let Some(command) = &output.postprocess {
+        body = postprocess(command, body)?;
+    }
 
-        write_cache(&cache_file, toml::to_string(&merged)?.as_bytes())?;
+    if body.len() > output.max_output_hard {
+        return Err(format!(
+            "merged config is {} bytes, over the limit of {} \
+             (STARSHIP_MULTI_MAX_OUTPUT_HARD); check for unintended sources",
+            body.len(),
+            output.max_output_hard
+        )
+        .into());
+    }
+    if body.len() > output.max_output {
+        eprintln!(
+            "starship-multi-config: warning: merged config is {} bytes, over {} \
+             (STARSHIP_MULTI_MAX_OUTPUT); check for unintended sources",
+            body.len(),
+            output.max_output
+        );
     }
 
-    println!("{}", cache_file.display());
-    Ok(())
+    let mut content = String::new();
+    if !output.no_header {
+        content.push_str(&header(paths));
+    }
+    content.push_str(&body);
+    Ok(content)
 }
 
-fn resolve_preset(bin_path: &Path, name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
-    let bin_mtime = fs::metadata(bin_path)
-        .and_then(|m| m.modified())
-        .map_err(|e| path_err(bin_path, e))?;
-
-    let hash = hash_key(|h| {
-        name.hash(h);
-        bin_path.hash(h);
-        bin_mtime.hash(h);
-        Ok(())
-    })?;
-
-    let cache_file = cache_dir()?.join(format!("preset-{hash}.toml"));
-
-    if !cache_file.exists() {
-        let output = Command::new(bin_path)
-            .args(["preset", name])
+/// Warn on stderr if `starship print-config` fails to load the merged config.
+fn verify(sources: &Sources, config: &Path) {
+    let result = sources.starship().and_then(|bin| {
+        Command::new(&bin)
+            .arg("print-config")
+            .env("STARSHIP_CONFIG", config)
+            .stdout(Stdio::null())
             .output()
-            .map_err(|e| format!("{}: {e}", bin_path.display()))?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(format!("starship preset {name}: {}", stderr.trim()).into());
+            .map_err(|e| format!("{}: {e}", bin.display()))
+    });
+
+    match result {
+        // Starship reports unknown keys as warnings without failing, so any stderr
+        // output counts as a complaint
+        Ok(output) if output.status.success() && output.stderr.trim_ascii().is_empty() => {
+            log::debug!("starship accepted {}", config.display());
         }
-
-        write_cache(&cache_file, &output.stdout)?;
+        Ok(output) => eprintln!(
+            "starship-multi-config: warning: starship print-config rejected {} ({}): {}",
+            config.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => eprintln!("starship-multi-config: warning: could not verify merged config: {e}"),
     }
-
-    Ok(cache_file)
 }
 
-fn cache_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
-    Ok(dirs::cache_dir()
-        .ok_or("could not determine cache directory")?
-        .join("starship-multi-config"))
-}
+/// Pipe merged TOML through an external command and return its validated output.
+fn postprocess(command: &Path, toml: String) -> Result<String, Box<dyn std::error::Error>> {
+    let command = expand_path(command);
+    let name = command.display();
+    let mut child = Command::new(&command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("postprocess {name}: {e}"))?;
+
+    // Feed stdin from another thread so a chatty command can't deadlock on full pipes
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let writer = thread::spawn(move || stdin.write_all(toml.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("postprocess {name}: {e}"))?;
+    // A command that exits without reading all of stdin is fine; only its output matters
+    let _ = writer.join();
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "postprocess {name} failed ({}): {}",
+            output.status,
+            stderr.trim()
+        )
+        .into());
+    }
 
-fn write_cache(path: &Path, content: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-    let dir = path.parent().ok_or("cache file has no parent directory")?;
-    fs::create_dir_all(dir)?;
-    let tmp = tempfile::NamedTempFile::new_in(dir)?;
-    fs::write(tmp.path(), content)?;
-    tmp.persist(path)?;
-    Ok(())
+    let result =
+        String::from_utf8(output.stdout).map_err(|e| format!("postprocess {name}: {e}"))?;
+    let result = normalize_newlines(result);
+    result
+        .parse::<toml::Table>()
+        .map_err(|e| format!("postprocess {name} produced invalid TOML: {e}"))?;
+    Ok(result)
 }
 
-fn hash_key(
-    f: impl FnOnce(&mut DefaultHasher) -> Result<(), Box<dyn std::error::Error>>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut h = DefaultHasher::new();
-    f(&mut h)?;
-    Ok(format!("{:x}", h.finish()))
+/// Comment block recording where a merged config came from. Starship ignores comments.
+fn header(paths: &[PathBuf]) -> String {
+    let mut header = format!(
+        "# Generated by starship-multi-config at {} from:\n",
+        humantime::format_rfc3339_seconds(SystemTime::now())
+    );
+    for path in paths {
+        header.push_str(&format!("#   {}\n", path.display()));
+    }
+    header.push('\n');
+    header
 }
 
 fn path_err(path: &Path, e: impl std::fmt::Display) -> String {
     format!("{}: {e}", path.display())
 }
 
-fn merge(base: &mut toml::Table, override_: &toml::Table) {
-    for (key, override_val) in override_ {
-        if let (Some(toml::Value::Table(b)), toml::Value::Table(o)) =
-            (base.get_mut(key), override_val)
-        {
-            merge(b, o);
-        } else {
-            base.insert(key.clone(), override_val.clone());
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn merge_toml(base: &str, override_: &str) -> String {
-        let mut base = base.parse::<toml::Table>().unwrap();
-        let override_ = override_.parse::<toml::Table>().unwrap();
-        merge(&mut base, &override_);
-        toml::to_string(&base).unwrap()
+    #[test]
+    fn toml_style_compact() {
+        let table = style_fixture();
+        insta::assert_snapshot!(TomlStyle::Compact.serialize(&table).unwrap());
     }
 
     #[test]
-    fn nested_table_merge_with_scalar_override() {
-        let base = r#"
+    fn toml_style_pretty() {
+        let table = style_fixture();
+        insta::assert_snapshot!(TomlStyle::Pretty.serialize(&table).unwrap());
+    }
+
+    fn style_fixture() -> toml::Table {
+        r#"
 format = "$all"
 
 [character]
 success_symbol = "[>](bold green)"
-error_symbol = "[>](bold red)"
-
-[git_branch]
-format = "[$branch]($style) "
-style = "bold purple"
-"#;
-
-        let override_ = r#"
-format = "$git_branch$character"
-
-[character]
-success_symbol = "[→](bold cyan)"
-vimcmd_symbol = "[←](bold cyan)"
-
-[package]
-disabled = true
-"#;
 
-        let merged = merge_toml(base, override_);
-        insta::assert_snapshot!(merged);
-    }
-
-    #[test]
-    fn array_replacement() {
-        let base = r#"
 [palettes.base]
 colors = ["red", "green", "blue"]
-"#;
-
-        let override_ = r#"
-[palettes.base]
-colors = ["cyan", "magenta"]
-"#;
-
-        let merged = merge_toml(base, override_);
-        insta::assert_snapshot!(merged);
+"#
+        .parse()
+        .unwrap()
     }
 }
@@ -0,0 +1,89 @@
+//! Manifests: TOML files listing the presets and layers to merge.
+
+use std::path::{Path, PathBuf};
+
+use crate::{merge::read_config, path_err, paths::expand_path};
+
+/// The sources listed in a manifest, e.g.
+///
+/// ```toml
+/// presets = ["nerd-font-symbols"]
+/// layers = ["base/*.toml", "~/.config/starship.local.toml"]
+///
+/// [profiles.work]
+/// layers = ["work.toml"]
+/// ```
+///
+/// A selected profile's presets and layers follow the top-level ones.
+pub struct Manifest {
+    /// Directory that relative layers resolve against.
+    pub dir: PathBuf,
+    /// Preset names, with relative local preset files already resolved.
+    pub presets: Vec<String>,
+    /// Layers in the same syntax as config arguments.
+    pub layers: Vec<PathBuf>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path, profile: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = read_config(path).map_err(|e| path_err(path, e))?;
+        let table: toml::Table = content.parse().map_err(|e| path_err(path, e))?;
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+        let mut presets = strings(&table, "presets", path)?;
+        let mut layers = strings(&table, "layers", path)?;
+        if let Some(name) = profile {
+            let profiles = table.get("profiles").and_then(toml::Value::as_table);
+            let Some(profile) = profiles.and_then(|p| p.get(name)?.as_table()) else {
+                let available: Vec<&str> = profiles
+                    .iter()
+                    .flat_map(|p| p.keys())
+                    .map(String::as_str)
+                    .collect();
+                return Err(path_err(
+                    path,
+                    format!("no profile `{name}` (available: {})", available.join(", ")),
+                )
+                .into());
+            };
+            presets.extend(strings(profile, "presets", path)?);
+            layers.extend(strings(profile, "layers", path)?);
+        }
+
+        let presets = presets
+            .into_iter()
+            .map(|name| {
+                if !(name.contains('/') || name.ends_with(".toml")) {
+                    return name;
+                }
+                let file = expand_path(Path::new(&name));
+                if file.is_relative() {
+                    dir.join(file).display().to_string()
+                } else {
+                    name
+                }
+            })
+            .collect();
+        let layers = layers.into_iter().map(PathBuf::from).collect();
+
+        log::debug!("loaded manifest {}", path.display());
+        Ok(Manifest {
+            dir,
+            presets,
+            layers,
+        })
+    }
+}
+
+/// The array of strings at `key`, or nothing if it's absent.
+fn strings(table: &toml::Table, key: &str, path: &Path) -> Result<Vec<String>, String> {
+    let invalid = || path_err(path, format!("`{key}` must be an array of strings"));
+    match table.get(key) {
+        None => Ok(Vec::new()),
+        Some(toml::Value::Array(items)) => items
+            .iter()
+            .map(|item| item.as_str().map(str::to_string).ok_or_else(invalid))
+            .collect(),
+        Some(_) => Err(invalid()),
+    }
+}
@@ -0,0 +1,837 @@
+//! Reading config files and deep-merging them.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use flate2::read::GzDecoder;
+
+use crate::{path_err, when};
+
+/// Read, parse, and deep-merge the given files left-to-right. Every file is parsed
+/// before any is merged, so one invalid file fails the whole merge.
+///
+/// With `type_check`, warn on stderr whenever a layer replaces a table with a
+/// non-table value (or vice versa), which usually indicates a structural mistake.
+/// Tables nested deeper than `max_depth` are replaced rather than merged.
+pub fn merge_files(
+    paths: &[PathBuf],
+    type_check: bool,
+    max_depth: usize,
+) -> Result<toml::Table, Box<dyn std::error::Error>> {
+    Ok(merge_tables(
+        paths,
+        parse_files(paths)?,
+        &[],
+        type_check,
+        max_depth,
+    ))
+}
+
+/// Read and parse the given files, failing on the first invalid one.
+pub fn parse_files(paths: &[PathBuf]) -> Result<Vec<toml::Table>, Box<dyn std::error::Error>> {
+    // Files are independent until merged, so read and parse them concurrently. Only the
+    // merge itself has to happen in order.
+    let tables = thread::scope(|scope| {
+        let parsers: Vec<_> = paths
+            .iter()
+            .map(|path| scope.spawn(|| parse_config(path)))
+            .collect();
+        parsers
+            .into_iter()
+            .map(|parser| parser.join().expect("parser thread panicked"))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+    Ok(tables)
+}
+
+/// Deep-merge already parsed `tables` in [`by_priority`] order, then `overrides` (from
+/// `--set`) over all of them. `paths` are the files the tables came from.
+pub fn merge_tables(
+    paths: &[PathBuf],
+    tables: Vec<toml::Table>,
+    overrides: &[toml::Table],
+    type_check: bool,
+    max_depth: usize,
+) -> toml::Table {
+    let mut merged = toml::Table::new();
+    for (path, table) in by_priority(paths, tables) {
+        merge_layer(&mut merged, path, &table, type_check, max_depth);
+    }
+    for table in overrides {
+        merge_layer(
+            &mut merged,
+            Path::new("--set"),
+            table,
+            type_check,
+            max_depth,
+        );
+    }
+    substitute_vars(&mut merged);
+    sanitize(&mut merged);
+    merged
+}
+
+/// Deep-merge one layer from `path` into `merged`, following the layer's top-level
+/// `__arrays__` setting: `"replace"` (the default), `"append"` to add its array
+/// elements after the ones below, or `"append-unique"` to also drop elements equal to an
+/// earlier one. A layer with `__fill_only__ = true` only adds keys that aren't set yet,
+/// never replacing a value from the layers below.
+///
+/// With `type_check`, warn on stderr whenever the layer replaces a table with a
+/// non-table value (or vice versa). Tables nested deeper than `max_depth` are replaced.
+pub fn merge_layer(
+    merged: &mut toml::Table,
+    path: &Path,
+    table: &toml::Table,
+    type_check: bool,
+    max_depth: usize,
+) {
+    let fill_only = match table.get("__fill_only__") {
+        None => false,
+        Some(toml::Value::Boolean(fill_only)) => *fill_only,
+        Some(other) => {
+            eprintln!(
+                "starship-multi-config: warning: {}: `__fill_only__` must be a boolean, not {}",
+                path.display(),
+                other.type_str()
+            );
+            false
+        }
+    };
+
+    let (append, unique) = match table.get("__arrays__") {
+        None => (false, false),
+        Some(toml::Value::String(s)) if s == "replace" => (false, false),
+        Some(toml::Value::String(s)) if s == "append" => (true, false),
+        Some(toml::Value::String(s)) if s == "append-unique" => (true, true),
+        Some(other) => {
+            eprintln!(
+                "starship-multi-config: warning: {}: `__arrays__` must be \"replace\", \
+                 \"append\", or \"append-unique\", not {other}",
+                path.display()
+            );
+            (false, false)
+        }
+    };
+
+    let mut check_type = |key: &str, base: &toml::Value, override_: &toml::Value| {
+        if type_check && base.is_table() != override_.is_table() {
+            eprintln!(
+                "starship-multi-config: warning: {}: `{key}` replaces {} with {}",
+                path.display(),
+                base.type_str(),
+                override_.type_str()
+            );
+        }
+    };
+    if fill_only {
+        merge_with(merged, table, max_depth, &mut |_, base, override_| {
+            if base.is_table() && override_.is_table() {
+                Resolution::Merge
+            } else {
+                Resolution::Base
+            }
+        });
+    } else if !append {
+        merge(merged, table, max_depth, &mut check_type);
+    } else {
+        merge_with(merged, table, max_depth, &mut |key, base, override_| {
+            check_type(key, base, override_);
+            match (base, override_) {
+                (toml::Value::Array(base), toml::Value::Array(override_)) => {
+                    let mut appended = toml::value::Array::new();
+                    for value in base.iter().chain(override_) {
+                        if !(unique && appended.contains(value)) {
+                            appended.push(value.clone());
+                        }
+                    }
+                    Resolution::Value(appended.into())
+                }
+                _ => Resolution::Merge,
+            }
+        });
+    }
+}
+
+/// Whether a layer only fills in keys the layers below left unset (`__fill_only__`).
+pub fn is_fill_only(table: &toml::Table) -> bool {
+    table.get("__fill_only__").and_then(toml::Value::as_bool) == Some(true)
+}
+
+/// Pair layers with their paths in merge order, stripping their top-level
+/// `__priority__` keys. Layers merge in ascending priority (0 by default), so a higher
+/// priority overrides a lower one; equal priorities keep their original order.
+///
+/// Layers whose top-level `__when__` condition is false are left out.
+pub fn by_priority(paths: &[PathBuf], tables: Vec<toml::Table>) -> Vec<(&Path, toml::Table)> {
+    let mut layers: Vec<(i64, &Path, toml::Table)> = paths
+        .iter()
+        .zip(tables)
+        .filter_map(|(path, mut table)| {
+            let enabled = match table.remove("__when__") {
+                None => true,
+                Some(toml::Value::String(expr)) => when::eval(&expr).unwrap_or_else(|e| {
+                    eprintln!(
+                        "starship-multi-config: warning: {}: invalid `__when__`: {e}",
+                        path.display()
+                    );
+                    true
+                }),
+                Some(other) => {
+                    eprintln!(
+                        "starship-multi-config: warning: {}: `__when__` must be a string, \
+                         not {}",
+                        path.display(),
+                        other.type_str()
+                    );
+                    true
+                }
+            };
+            if !enabled {
+                log::debug!("skipping {}: `__when__` is false", path.display());
+                return None;
+            }
+
+            let priority = match table.remove("__priority__") {
+                None => 0,
+                Some(toml::Value::Integer(priority)) => priority,
+                Some(other) => {
+                    eprintln!(
+                        "starship-multi-config: warning: {}: `__priority__` must be an \
+                         integer, not {}",
+                        path.display(),
+                        other.type_str()
+                    );
+                    0
+                }
+            };
+            Some((priority, path.as_path(), table))
+        })
+        .collect();
+    // `sort_by_key` is stable
+    layers.sort_by_key(|(priority, _, _)| *priority);
+    layers
+        .into_iter()
+        .map(|(_, path, table)| (path, table))
+        .collect()
+}
+
+/// Remove the `[palettes.*]` tables that the top-level `palette` key doesn't name,
+/// since starship only uses that one. Without a `palette`, all are removed.
+pub fn prune_palettes(merged: &mut toml::Table) {
+    let active = merged
+        .get("palette")
+        .and_then(toml::Value::as_str)
+        .map(str::to_string);
+    let Some(toml::Value::Table(palettes)) = merged.get_mut("palettes") else {
+        return;
+    };
+    palettes.retain(|name, _| {
+        let keep = active.as_deref().is_some_and(|active| active == name);
+        if !keep {
+            log::debug!("pruning unused palette `{name}`");
+        }
+        keep
+    });
+    if palettes.is_empty() {
+        merged.remove("palettes");
+    }
+}
+
+/// Parse a `--set` assignment like `character.success_symbol = ">"` into a layer that
+/// sets just that key. The value is TOML.
+pub fn parse_assignment(assignment: &str) -> Result<toml::Table, String> {
+    let (key, value) = assignment.split_once('=').ok_or("expected `KEY=VALUE`")?;
+    let value = format!("value = {value}")
+        .parse::<toml::Table>()
+        .map_err(|e| format!("invalid value: {}", e.message()))?
+        .remove("value")
+        .ok_or("missing value")?;
+    let parts: Vec<&str> = key.split('.').map(str::trim).collect();
+    let Some((last, parents)) = parts.split_last().filter(|_| !parts.contains(&"")) else {
+        return Err(format!("invalid key `{}`", key.trim()));
+    };
+    let mut table = toml::Table::from_iter([(last.to_string(), value)]);
+    for parent in parents.iter().rev() {
+        table = toml::Table::from_iter([(parent.to_string(), table.into())]);
+    }
+    Ok(table)
+}
+
+/// Keys that configure the merge itself rather than starship.
+const RESERVED_KEYS: &[&str] = &[
+    "__arrays__",
+    "__fill_only__",
+    "__priority__",
+    "__vars__",
+    "__when__",
+];
+
+/// Remove everything meant for this tool rather than starship from the merged config,
+/// at any depth: [`RESERVED_KEYS`] and leftover [`DELETE`] markers. Run this last, so
+/// that no merge feature has to clean up after itself for the output to be valid.
+pub fn sanitize(merged: &mut toml::Table) {
+    merged.retain(|key, value| !RESERVED_KEYS.contains(&key) && value.as_str() != Some(DELETE));
+    for (_, value) in merged.iter_mut() {
+        sanitize_value(value);
+    }
+}
+
+/// Whether [`sanitize`] would leave `table` as it is, i.e. nothing in it is meant for
+/// this tool.
+pub fn is_plain(table: &toml::Table) -> bool {
+    let mut sanitized = table.clone();
+    sanitize(&mut sanitized);
+    sanitized == *table
+}
+
+fn sanitize_value(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => sanitize(table),
+        toml::Value::Array(values) => {
+            values.retain(|value| value.as_str() != Some(DELETE));
+            values.iter_mut().for_each(sanitize_value);
+        }
+        _ => {}
+    }
+}
+
+/// Prefix of a reference to a variable defined in the top-level `[__vars__]` table.
+const VAR_PREFIX: &str = "$__vars__.";
+
+/// Replace `$__vars__.<name>` references in the merged config with the values from its
+/// `[__vars__]` table, then drop that table. Since this runs after merging, every layer
+/// sees the variables from all layers, with later definitions winning.
+///
+/// A string that is exactly one reference takes on the variable's value (of any type);
+/// references embedded in a longer string are interpolated.
+pub fn substitute_vars(merged: &mut toml::Table) {
+    let Some(vars) = merged.remove("__vars__") else {
+        return;
+    };
+    let toml::Value::Table(vars) = vars else {
+        eprintln!("starship-multi-config: warning: `__vars__` must be a table");
+        return;
+    };
+    for (key, value) in merged.iter_mut() {
+        substitute(value, &vars, key);
+    }
+}
+
+fn substitute(value: &mut toml::Value, vars: &toml::Table, key_path: &str) {
+    match value {
+        toml::Value::String(s) => {
+            if let Some(var) = s.strip_prefix(VAR_PREFIX).and_then(|name| vars.get(name)) {
+                *value = var.clone();
+            } else if s.contains(VAR_PREFIX) {
+                *s = interpolate(s, vars, key_path);
+            }
+        }
+        toml::Value::Array(values) => {
+            for value in values {
+                substitute(value, vars, key_path);
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                substitute(value, vars, &format!("{key_path}.{key}"));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn interpolate(s: &str, vars: &toml::Table, key_path: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find(VAR_PREFIX) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + VAR_PREFIX.len()..];
+        let end = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(after.len());
+        let name = &after[..end];
+        match vars.get(name) {
+            Some(toml::Value::String(var)) => out.push_str(var),
+            Some(var) => out.push_str(&var.to_string()),
+            None => {
+                eprintln!(
+                    "starship-multi-config: warning: `{key_path}` references undefined \
+                     variable `{VAR_PREFIX}{name}`"
+                );
+                out.push_str(&rest[start..start + VAR_PREFIX.len() + end]);
+            }
+        }
+        rest = &after[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A value that removes its key from the layers below instead of setting it. JSON
+/// layers use it for `null`, which TOML has no equivalent for.
+pub const DELETE: &str = "__delete__";
+
+/// Prefix of comment lines in a layer's header that set its merge behavior, e.g.
+/// `# multi: arrays=append, priority=50`.
+const DIRECTIVE_PREFIX: &str = "# multi:";
+
+pub fn parse_config(path: &Path) -> Result<toml::Table, String> {
+    log::trace!("parsing {}", path.display());
+    let content = read_config(path).map_err(|e| path_err(path, e))?;
+    if is_json(path) {
+        let json: serde_json::Value =
+            serde_json::from_str(&content).map_err(|e| path_err(path, e))?;
+        return match json_to_toml(json).map_err(|e| path_err(path, e))? {
+            toml::Value::Table(table) => Ok(table),
+            _ => Err(path_err(path, "expected a JSON object")),
+        };
+    }
+    if is_ini(path) {
+        return crate::ini::parse(&content).map_err(|e| path_err(path, e));
+    }
+    let mut table = content
+        .parse::<toml::Table>()
+        .map_err(|e| path_err(path, e))?;
+    apply_directives(path, &content, &mut table);
+    Ok(table)
+}
+
+/// Apply `# multi:` directives from the comment lines at the top of `content` to its
+/// parsed `table`, as the reserved keys they stand for. This keeps the file a valid
+/// standalone starship config. Keys set in the TOML body take precedence.
+fn apply_directives(path: &Path, content: &str, table: &mut toml::Table) {
+    for (name, value) in directives(content) {
+        let value: toml::Value = match name {
+            "arrays" => value.into(),
+            // Resolved along with the other sources, see `sources::expand_includes`
+            "include" => continue,
+            "priority" => match value.parse::<i64>() {
+                Ok(priority) => priority.into(),
+                Err(_) => {
+                    eprintln!(
+                        "starship-multi-config: warning: {}: `priority` directive must be \
+                         an integer, not `{value}`",
+                        path.display()
+                    );
+                    continue;
+                }
+            },
+            _ => {
+                eprintln!(
+                    "starship-multi-config: warning: {}: unknown directive `{name}`",
+                    path.display()
+                );
+                continue;
+            }
+        };
+        table.entry(format!("__{name}__")).or_insert(value);
+    }
+}
+
+/// The `name=value` pairs of the `# multi:` directives in the comment lines at the top
+/// of `content`, trimmed.
+pub fn directives(content: &str) -> impl Iterator<Item = (&str, &str)> {
+    content
+        .lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with('#'))
+        .filter_map(|line| line.strip_prefix(DIRECTIVE_PREFIX))
+        .flat_map(|directives| directives.split(','))
+        .map(|directive| {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            (name.trim(), value.trim())
+        })
+}
+
+/// `content` without its `include=` directives, for a copy of the layer that sits
+/// right after copies of the files it includes. Other directives are kept.
+pub fn strip_includes(content: &str) -> String {
+    let mut header = true;
+    let mut out = String::with_capacity(content.len());
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        header &= trimmed.is_empty() || trimmed.starts_with('#');
+        let Some(directives) = trimmed.strip_prefix(DIRECTIVE_PREFIX).filter(|_| header) else {
+            out.push_str(line);
+            continue;
+        };
+        let kept: Vec<&str> = directives
+            .split(',')
+            .filter(|directive| {
+                directive
+                    .split_once('=')
+                    .is_none_or(|(name, _)| name.trim() != "include")
+            })
+            .map(str::trim)
+            .collect();
+        if !kept.is_empty() {
+            out.push_str(&format!("{DIRECTIVE_PREFIX} {}\n", kept.join(", ")));
+        }
+    }
+    out
+}
+
+/// Whether `path` is a JSON layer (`.json`, optionally gzipped) rather than TOML.
+pub fn is_json(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.strip_suffix(".gz").unwrap_or(&name).ends_with(".json")
+}
+
+/// Whether `path` is an INI layer (`.ini` or `.conf`, optionally gzipped).
+pub fn is_ini(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    name.ends_with(".ini") || name.ends_with(".conf")
+}
+
+/// Whether `path` is a TOML layer, as opposed to one converted from another format.
+pub fn is_toml(path: &Path) -> bool {
+    !is_json(path) && !is_ini(path)
+}
+
+/// Convert a JSON value to TOML, with `null` object values becoming [`DELETE`].
+fn json_to_toml(json: serde_json::Value) -> Result<toml::Value, String> {
+    use serde_json::Value;
+    Ok(match json {
+        Value::Null => DELETE.into(),
+        Value::Bool(b) => b.into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n
+                .as_f64()
+                .ok_or_else(|| format!("number {n} is out of range"))?
+                .into(),
+        },
+        Value::String(s) => s.into(),
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::Null => Err("arrays can't contain null".to_string()),
+                value => json_to_toml(value),
+            })
+            .collect::<Result<toml::value::Array, _>>()?
+            .into(),
+        Value::Object(map) => map
+            .into_iter()
+            .map(|(key, value)| Ok((key, json_to_toml(value)?)))
+            .collect::<Result<toml::Table, String>>()?
+            .into(),
+    })
+}
+
+/// Read a config file, transparently decompressing `.gz` files. CRLF line endings are
+/// normalized to LF, so multi-line strings from Windows-edited files don't carry `\r`
+/// into the merged config.
+pub fn read_config(path: &Path) -> io::Result<String> {
+    let content = if path.extension().is_some_and(|ext| ext == "gz") {
+        let mut content = Vec::new();
+        GzDecoder::new(fs::File::open(path)?).read_to_end(&mut content)?;
+        content
+    } else {
+        fs::read(path)?
+    };
+    let content = String::from_utf8(content)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, NotUtf8))?;
+    Ok(normalize_newlines(content))
+}
+
+/// The error for a config file that isn't text, e.g. a binary file matched by a glob.
+#[derive(Debug)]
+pub struct NotUtf8;
+
+impl std::fmt::Display for NotUtf8 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("not valid UTF-8")
+    }
+}
+
+impl std::error::Error for NotUtf8 {}
+
+/// Replace CRLF line endings with LF.
+pub fn normalize_newlines(content: String) -> String {
+    if content.contains('\r') {
+        content.replace("\r\n", "\n")
+    } else {
+        content
+    }
+}
+
+/// Deep-merge `override_` into `base`, calling `on_replace` with the dotted key path
+/// whenever an existing value is replaced rather than merged.
+pub fn merge(
+    base: &mut toml::Table,
+    override_: &toml::Table,
+    max_depth: usize,
+    on_replace: &mut dyn FnMut(&str, &toml::Value, &toml::Value),
+) {
+    merge_with(
+        base,
+        override_,
+        max_depth,
+        &mut |path, base_val, override_val| {
+            if base_val.is_table() && override_val.is_table() {
+                Resolution::Merge
+            } else {
+                on_replace(path, base_val, override_val);
+                Resolution::Override
+            }
+        },
+    );
+}
+
+/// How [`merge_with`] combines a key set in both tables.
+pub enum Resolution {
+    /// Keep the base value.
+    Base,
+    /// Take the override value.
+    Override,
+    /// Deep-merge two tables. Other values take the override.
+    Merge,
+    /// Use a new value, e.g. combining both.
+    Value(toml::Value),
+}
+
+/// Merge `override_` into `base`, letting `resolver` decide how each key set in both is
+/// combined. It's called with the dotted key path, the base value, and the override.
+/// Keys only in `override_` are always added, and keys set to [`DELETE`] are removed.
+/// Tables nested more than `max_depth` deep are replaced instead of merged, with a
+/// warning.
+pub fn merge_with(
+    base: &mut toml::Table,
+    override_: &toml::Table,
+    max_depth: usize,
+    resolver: &mut dyn FnMut(&str, &toml::Value, &toml::Value) -> Resolution,
+) {
+    merge_at(base, override_, "", 0, max_depth, resolver);
+}
+
+/// The default for `--merge-max-depth`. Real configs are nowhere near it.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+fn merge_at(
+    base: &mut toml::Table,
+    override_: &toml::Table,
+    prefix: &str,
+    depth: usize,
+    max_depth: usize,
+    resolver: &mut dyn FnMut(&str, &toml::Value, &toml::Value) -> Resolution,
+) {
+    for (key, override_val) in override_ {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        if override_val.as_str() == Some(DELETE) {
+            base.remove(key);
+            continue;
+        }
+        let Some(base_val) = base.get_mut(key) else {
+            // Merge new tables into an empty one so nested deletes are dropped
+            let value = match override_val {
+                toml::Value::Table(o) if depth < max_depth => {
+                    let mut table = toml::Table::new();
+                    merge_at(&mut table, o, &path, depth + 1, max_depth, resolver);
+                    table.into()
+                }
+                _ => override_val.clone(),
+            };
+            base.insert(key.clone(), value);
+            continue;
+        };
+        match (
+            resolver(&path, base_val, override_val),
+            base_val,
+            override_val,
+        ) {
+            (Resolution::Base, _, _) => {}
+            (Resolution::Merge, toml::Value::Table(b), toml::Value::Table(o))
+                if depth < max_depth =>
+            {
+                merge_at(b, o, &path, depth + 1, max_depth, resolver);
+            }
+            (Resolution::Merge, base_val @ toml::Value::Table(_), toml::Value::Table(_)) => {
+                eprintln!(
+                    "starship-multi-config: warning: `{path}` is nested more than {max_depth} \
+                     tables deep; replacing it instead of merging"
+                );
+                *base_val = override_val.clone();
+            }
+            (Resolution::Override | Resolution::Merge, base_val, _) => {
+                *base_val = override_val.clone();
+            }
+            (Resolution::Value(value), base_val, _) => *base_val = value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge_toml(base: &str, override_: &str) -> String {
+        let mut base = base.parse::<toml::Table>().unwrap();
+        let override_ = override_.parse::<toml::Table>().unwrap();
+        merge(&mut base, &override_, DEFAULT_MAX_DEPTH, &mut |_, _, _| {});
+        toml::to_string(&base).unwrap()
+    }
+
+    #[test]
+    fn nested_table_merge_with_scalar_override() {
+        let base = r#"
+format = "$all"
+
+[character]
+success_symbol = "[>](bold green)"
+error_symbol = "[>](bold red)"
+
+[git_branch]
+format = "[$branch]($style) "
+style = "bold purple"
+"#;
+
+        let override_ = r#"
+format = "$git_branch$character"
+
+[character]
+success_symbol = "[→](bold cyan)"
+vimcmd_symbol = "[←](bold cyan)"
+
+[package]
+disabled = true
+"#;
+
+        let merged = merge_toml(base, override_);
+        insta::assert_snapshot!(merged);
+    }
+
+    #[test]
+    fn parallel_parse_keeps_layer_order() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let paths: Vec<PathBuf> = (0..16)
+            .map(|i| {
+                let path = dir.path().join(format!("{i}.toml"));
+                let content = format!("format = \"{i}\"\n\n[layer_{i}]\nindex = {i}\n");
+                fs::write(&path, content).unwrap();
+                path
+            })
+            .collect();
+
+        let mut serial = toml::Table::new();
+        for path in &paths {
+            merge(
+                &mut serial,
+                &parse_config(path).unwrap(),
+                DEFAULT_MAX_DEPTH,
+                &mut |_, _, _| {},
+            );
+        }
+
+        assert_eq!(
+            merge_files(&paths, false, DEFAULT_MAX_DEPTH).unwrap(),
+            serial
+        );
+    }
+
+    #[test]
+    fn table_palette_merges_by_color_name() {
+        let base = r##"
+palette = "base"
+
+[palettes.base]
+red = "#ff5555"
+green = "#50fa7b"
+"##;
+
+        let override_ = r##"
+[palettes.base]
+green = "#00ff00"
+blue = "#6272a4"
+"##;
+
+        let merged = merge_toml(base, override_);
+        insta::assert_snapshot!(merged);
+    }
+
+    #[test]
+    fn custom_resolver_concatenates_strings() {
+        let mut base = r#"
+format = "$directory"
+
+[character]
+success_symbol = ">"
+error_symbol = "x"
+"#
+        .parse::<toml::Table>()
+        .unwrap();
+        let override_ = r#"
+format = "$character"
+
+[character]
+success_symbol = "!"
+error_symbol = "!"
+"#
+        .parse::<toml::Table>()
+        .unwrap();
+
+        merge_with(
+            &mut base,
+            &override_,
+            DEFAULT_MAX_DEPTH,
+            &mut |path, b, o| match (b, o) {
+                _ if path == "character.error_symbol" => Resolution::Base,
+                (toml::Value::String(b), toml::Value::String(o)) => {
+                    Resolution::Value(format!("{b}{o}").into())
+                }
+                _ => Resolution::Merge,
+            },
+        );
+
+        assert_eq!(base["format"].as_str(), Some("$directory$character"));
+        assert_eq!(base["character"]["success_symbol"].as_str(), Some(">!"));
+        assert_eq!(base["character"]["error_symbol"].as_str(), Some("x"));
+    }
+
+    #[test]
+    fn array_replacement() {
+        let base = r#"
+[palettes.base]
+colors = ["red", "green", "blue"]
+"#;
+
+        let override_ = r#"
+[palettes.base]
+colors = ["cyan", "magenta"]
+"#;
+
+        let merged = merge_toml(base, override_);
+        insta::assert_snapshot!(merged);
+    }
+
+    #[test]
+    fn assignment_nests_dotted_keys() {
+        let layer = parse_assignment("palettes.mine.red = '#ff0000'").unwrap();
+        assert_eq!(
+            toml::to_string(&layer).unwrap(),
+            "[palettes.mine]\nred = \"#ff0000\"\n"
+        );
+        assert_eq!(
+            parse_assignment("add_newline=false").unwrap()["add_newline"],
+            toml::Value::Boolean(false)
+        );
+
+        assert_eq!(
+            parse_assignment("format"),
+            Err("expected `KEY=VALUE`".to_string())
+        );
+        assert_eq!(
+            parse_assignment("character..symbol=1"),
+            Err("invalid key `character..symbol`".to_string())
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Expansion of user-supplied paths.
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// Expand a leading `~` to the home directory and `$VAR` or `${VAR}` to environment
+/// variables, for paths given on the command line or in env vars. Unset variables and
+/// paths that aren't UTF-8 are left as they are.
+pub fn expand_path(path: &Path) -> PathBuf {
+    let Some(path) = path.to_str() else {
+        return path.to_path_buf();
+    };
+    let path = expand_vars(path);
+
+    if let Some(rest) = path.strip_prefix('~')
+        && (rest.is_empty() || rest.starts_with('/'))
+        && let Some(home) = dirs::home_dir()
+    {
+        return home.join(rest.trim_start_matches('/'));
+    }
+    PathBuf::from(path)
+}
+
+fn expand_vars(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+
+        match env::var(name) {
+            Ok(value) if !name.is_empty() => out.push_str(&value),
+            _ => out.push_str(&rest[start..start + 1 + len]),
+        }
+        rest = &after[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_tilde_and_vars() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_path(Path::new("~")), home);
+        assert_eq!(
+            expand_path(Path::new("~/.config/starship.toml")),
+            home.join(".config/starship.toml")
+        );
+        assert_eq!(
+            expand_path(Path::new("~other/starship.toml")),
+            Path::new("~other/starship.toml")
+        );
+
+        let path = env::var("PATH").unwrap();
+        assert_eq!(
+            expand_path(Path::new("$PATH/x")),
+            Path::new(&format!("{path}/x"))
+        );
+        assert_eq!(
+            expand_path(Path::new("${PATH}x")),
+            Path::new(&format!("{path}x"))
+        );
+        assert_eq!(
+            expand_path(Path::new(
+                "$STARSHIP_MULTI_UNSET_VAR/a/${STARSHIP_MULTI_UNSET_VAR}"
+            )),
+            Path::new("$STARSHIP_MULTI_UNSET_VAR/a/${STARSHIP_MULTI_UNSET_VAR}")
+        );
+        assert_eq!(expand_path(Path::new("a$/${b")), Path::new("a$/${b"));
+    }
+}
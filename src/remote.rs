@@ -0,0 +1,124 @@
+//! Layers and presets fetched over HTTPS or from git repositories, cached for a TTL.
+
+use std::{
+    env, fs,
+    hash::Hash,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime},
+};
+
+use crate::cache::{hash_key, usable_cache_dir, write_cache, write_temp};
+
+/// How long to wait for a remote config before giving up, so an unreachable server
+/// can't hang the prompt.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether a config argument or preset is a URL to fetch rather than a local path.
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("https://") || s.starts_with("git+") || (allow_http() && s.starts_with("http://"))
+}
+
+/// Plain `http://` would let anyone on the network inject `[custom.*]` commands that
+/// starship runs on every prompt, so it's only accepted by debug builds with
+/// `STARSHIP_MULTI_TEST_ALLOW_HTTP` set, for tests against a local server.
+fn allow_http() -> bool {
+    cfg!(debug_assertions) && env::var_os("STARSHIP_MULTI_TEST_ALLOW_HTTP").is_some()
+}
+
+/// Fetch `url` into a cache file named `remote-<hash of url>.toml`, reusing a copy
+/// younger than `ttl`. If fetching fails, an older copy is used with a warning.
+///
+/// A `git+<repo>#<path>[@<ref>]` URL reads one file from a git repository at `ref`
+/// (`HEAD` by default), using the `git` on `PATH`.
+pub fn resolve_remote(
+    url: &str,
+    ttl: Duration,
+    readonly: bool,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let Some(dir) = usable_cache_dir(readonly) else {
+        return write_temp(fetch(url)?.as_bytes());
+    };
+
+    let hash = hash_key(|h| {
+        url.hash(h);
+        Ok(())
+    })?;
+    let cache_file = dir.join(format!("remote-{hash}.toml"));
+
+    if let Some(age) = age(&cache_file)
+        && age < ttl
+    {
+        log::debug!("cache hit for {url}: {}", cache_file.display());
+        return Ok(cache_file);
+    }
+
+    match fetch(url) {
+        Ok(body) => write_cache(&cache_file, body.as_bytes()),
+        Err(e) if cache_file.exists() => {
+            eprintln!("starship-multi-config: warning: {e}; using the cached copy");
+            Ok(cache_file)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn fetch(url: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if url.starts_with("git+") {
+        return fetch_git(url);
+    }
+    log::debug!("fetching {url}");
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(FETCH_TIMEOUT))
+        .build()
+        .into();
+    let body = agent
+        .get(url)
+        .call()
+        .and_then(|mut response| response.body_mut().read_to_string())
+        .map_err(|e| format!("{url}: {e}"))?;
+    body.parse::<toml::Table>()
+        .map_err(|e| format!("{url}: {e}"))?;
+    Ok(body)
+}
+
+fn fetch_git(url: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let (repo, file) = url
+        .strip_prefix("git+")
+        .and_then(|spec| spec.split_once('#'))
+        .ok_or_else(|| format!("{url}: expected git+<repo>#<path>[@<ref>]"))?;
+    let (file, rev) = file.rsplit_once('@').unwrap_or((file, "HEAD"));
+    log::debug!("fetching {file} at {rev} from {repo}");
+
+    // Fetch only the one commit into a throwaway repository
+    let dir = tempfile::tempdir()?;
+    let git = |args: &[&str]| {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir.path())
+            .args(args)
+            // Fail instead of waiting for credentials that nobody will type
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .output()
+            .map_err(|e| format!("{url}: git: {e}"))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("{url}: git {} failed: {}", args[0], stderr.trim()));
+        }
+        Ok(output.stdout)
+    };
+    git(&["init", "--quiet", "--bare"])?;
+    git(&["fetch", "--quiet", "--depth=1", repo, rev])?;
+    let body = git(&["show", &format!("FETCH_HEAD:{file}")])?;
+
+    let body = String::from_utf8(body).map_err(|_| format!("{url}: not valid UTF-8"))?;
+    body.parse::<toml::Table>()
+        .map_err(|e| format!("{url}: {e}"))?;
+    Ok(body)
+}
+
+/// Time since `path` was last written, or `None` if it doesn't exist.
+fn age(path: &Path) -> Option<Duration> {
+    let mtime = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    Some(SystemTime::now().duration_since(mtime).unwrap_or_default())
+}
@@ -0,0 +1,915 @@
+//! Resolving presets and config arguments into the files to merge.
+
+use std::{
+    cmp::Ordering,
+    env, fs,
+    hash::Hash,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use clap::{CommandFactory, error::ErrorKind};
+
+use crate::{
+    Cli, GlobSort, PresetCacheKey, Seed, Sources, builtin,
+    cache::{cache_dir, hash_key, usable_cache_dir, write_cache, write_temp},
+    manifest::Manifest,
+    merge::{NotUtf8, directives, is_toml, parse_config, read_config},
+    path_err,
+    paths::expand_path,
+    remote::{is_url, resolve_remote},
+};
+
+/// Where a resolved source came from.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SourceKind {
+    /// A preset, or starship's defaults for `--seed default`
+    Preset,
+    /// A config file, directory entry, or drop-in
+    Config,
+    /// A file named by another source's `include` directive
+    Include,
+}
+
+impl SourceKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SourceKind::Preset => "preset",
+            SourceKind::Config => "config",
+            SourceKind::Include => "include",
+        }
+    }
+}
+
+/// Resolve the seed, manifest, presets, config directories, and config arguments into
+/// the ordered list of files to merge.
+pub fn resolve_sources(sources: &Sources) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let paths = resolve_source_kinds(sources)?;
+    Ok(paths.into_iter().map(|(path, _)| path).collect())
+}
+
+/// Like [`resolve_sources`], along with where each file came from.
+pub fn resolve_source_kinds(
+    sources: &Sources,
+) -> Result<Vec<(PathBuf, SourceKind)>, Box<dyn std::error::Error>> {
+    // An escape hatch for debugging: exactly this file, ignoring all other sources
+    if let Some(config) = &sources.force_config {
+        let config = expand_path(config);
+        log::info!("forcing config {}", config.display());
+        return Ok(vec![(
+            fs::canonicalize(&config).map_err(|e| path_err(&config, e))?,
+            SourceKind::Config,
+        )]);
+    }
+
+    let mut paths = resolve_layers(sources)?;
+
+    // Guard against an overly broad glob or directory making the prompt hang while
+    // merging. Counted once resolved, so drop-ins and includes count too.
+    if paths.len() > sources.config_max {
+        return Err(format!(
+            "too many config files ({}, limit {}); raise STARSHIP_MULTI_CONFIG_MAX if this \
+             is intended",
+            paths.len(),
+            sources.config_max
+        )
+        .into());
+    }
+    if sources.seed == Seed::Default {
+        let defaults = cached_starship_output(
+            &sources.starship()?,
+            &["print-config", "--default"],
+            "default",
+            false,
+            None,
+            PresetCacheKey::Binary,
+            sources.readonly,
+        )?;
+        paths.insert(
+            0,
+            (
+                fs::canonicalize(&defaults).unwrap_or(defaults),
+                SourceKind::Preset,
+            ),
+        );
+    }
+
+    Ok(paths)
+}
+
+fn resolve_layers(
+    sources: &Sources,
+) -> Result<Vec<(PathBuf, SourceKind)>, Box<dyn std::error::Error>> {
+    if sources.preset.is_empty()
+        && sources.configs.is_empty()
+        && sources.config_dir.is_none()
+        && sources.manifest.is_none()
+    {
+        let paths = default_sources()?;
+        if paths.is_empty() {
+            Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "no config files specified, and no default config found in ~/.config",
+                )
+                .exit();
+        }
+        return as_configs(paths);
+    }
+
+    // A manifest's presets and layers go before the ones given directly
+    let manifest = match &sources.manifest {
+        Some(path) => Some(Manifest::load(
+            &expand_path(path),
+            sources.profile.as_deref(),
+        )?),
+        None if sources.profile.is_some() => {
+            return Err("a profile was selected, but there's no manifest to define it".into());
+        }
+        None => None,
+    };
+
+    // Resolve preset configs if --preset is set. The starship binary is only looked up
+    // once a named preset needs it.
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut starship = None;
+    let presets = manifest.iter().flat_map(|m| &m.presets);
+    for name in presets.chain(&sources.preset) {
+        if is_url(name) {
+            paths.push(resolve_remote(name, sources.remote_ttl, sources.readonly)?);
+            continue;
+        }
+        if let Some(builtin) = name.strip_prefix(builtin::PREFIX) {
+            let content = builtin::preset(builtin)?;
+            paths.push(cache_content(
+                "builtin",
+                content.as_bytes(),
+                sources.readonly,
+            )?);
+            continue;
+        }
+        if name.contains('/') || name.ends_with(".toml") {
+            // A local preset file is merged directly, so the cache follows its mtime
+            paths.push(expand_path(Path::new(name)));
+            continue;
+        }
+
+        let (bin, version) = match &starship {
+            Some(starship) => starship,
+            None => {
+                let bin = sources.starship()?;
+                let version = if sources.preset_version || sources.preset_auto_refresh {
+                    Some(starship_version(&bin)?)
+                } else {
+                    None
+                };
+                if sources.preset_auto_refresh
+                    && let Some(version) = &version
+                {
+                    refresh_presets(version, sources.readonly)?;
+                }
+                starship.insert((bin, version.filter(|_| sources.preset_version)))
+            }
+        };
+        paths.push(cached_starship_output(
+            bin,
+            &["preset", name],
+            "preset",
+            sources.preset_no_cache,
+            version.as_deref(),
+            sources.preset_cache_key,
+            sources.readonly,
+        )?);
+    }
+
+    let preset_count = paths.len();
+
+    // Generated files are never picked up from the cache directory, see `skip_cached`
+    let cache = cache_dir()
+        .ok()
+        .map(|dir| fs::canonicalize(&dir).unwrap_or(dir));
+    let cache = cache.as_deref();
+
+    // Config directories add their drop-ins as base layers, one directory after another
+    for dir in sources.config_dir.iter().flat_map(env::split_paths) {
+        let dir = expand_path(&dir);
+        if dir.is_dir() {
+            let files = toml_files_in(&dir).map_err(|e| path_err(&dir, e))?;
+            paths.extend(skip_cached(files, cache));
+        } else {
+            log::debug!("skipping config dir {}: not a directory", dir.display());
+        }
+    }
+
+    // `-` reads a layer from stdin, which is materialized once into the cache
+    let mut stdin_path: Option<PathBuf> = None;
+    let mut unmatched_glob = false;
+    let config_home = sources.config_home.as_deref().map(expand_path);
+    let configs = manifest
+        .iter()
+        .flat_map(|m| m.layers.iter().map(|layer| (layer, Some(m.dir.as_path()))))
+        .chain(sources.configs.iter().map(|c| (c, config_home.as_deref())));
+    // Where the first config's files start, for `--require-first`
+    let mut first_config = None;
+    for (i, (config, base)) in configs.enumerate() {
+        let Some(config) = strip_env_conditions(config) else {
+            if i == 0 && sources.require_first {
+                return Err(format!(
+                    "{}: required first config is skipped by its env condition",
+                    config.display()
+                )
+                .into());
+            }
+            log::debug!("skipping {}: env condition not met", config.display());
+            continue;
+        };
+        if i == 0 {
+            first_config = Some(paths.len());
+        }
+
+        if config.as_os_str() == "-" {
+            if stdin_path.is_none() {
+                stdin_path = Some(resolve_stdin(sources.readonly)?);
+            }
+            paths.extend(stdin_path.clone());
+        } else if let Some(url) = config.to_str().filter(|c| is_url(c)) {
+            paths.push(resolve_remote(url, sources.remote_ttl, sources.readonly)?);
+        } else {
+            // `path.toml#key#...` merges only the given top-level keys of the file
+            let (config, scopes) = split_scopes(config, base);
+
+            let matches = skip_cached(
+                expand_glob(config, sources.glob_numeric_sort, sources.glob_sort)?,
+                cache,
+            );
+            unmatched_glob |= matches.is_empty();
+            for config in matches {
+                // A directory stands for its `*.toml` files, like a `--config-dir` entry
+                if config.is_dir() {
+                    let files = toml_files_in(&config).map_err(|e| path_err(&config, e))?;
+                    for file in skip_cached(files, cache) {
+                        paths.push(if scopes.is_empty() {
+                            file
+                        } else {
+                            resolve_scoped(&file, &scopes, sources.readonly)?
+                        });
+                    }
+                    continue;
+                }
+
+                if !scopes.is_empty() {
+                    paths.push(resolve_scoped(&config, &scopes, sources.readonly)?);
+                    continue;
+                }
+
+                // A sibling `<config>.d/` directory holds drop-in overrides for the config
+                let mut drop_in_dir = config.clone().into_os_string();
+                drop_in_dir.push(".d");
+                let drop_in_dir = PathBuf::from(drop_in_dir);
+
+                paths.push(config);
+                if drop_in_dir.is_dir() {
+                    let files =
+                        toml_files_in(&drop_in_dir).map_err(|e| path_err(&drop_in_dir, e))?;
+                    paths.extend(skip_cached(files, cache));
+                }
+            }
+        }
+
+        if i == 0 && sources.require_first && paths.len() == first_config.unwrap_or_default() {
+            return Err(format!(
+                "{}: required first config matched no files",
+                config.display()
+            )
+            .into());
+        }
+    }
+    let first_config = first_config
+        .and_then(|i| paths.get(i))
+        .map(|path| fs::canonicalize(path).unwrap_or_else(|_| path.clone()));
+
+    let paths = paths.into_iter().enumerate().map(|(i, path)| {
+        let kind = if i < preset_count {
+            SourceKind::Preset
+        } else {
+            SourceKind::Config
+        };
+        (path, kind)
+    });
+    let mut paths = expand_all(paths)?;
+
+    // Skipping needs each file read up front, since the cache key depends on the list
+    if sources.skip_unreadable {
+        paths.retain(|(path, _)| match read_config(path) {
+            Err(e) if e.get_ref().is_some_and(|e| e.is::<NotUtf8>()) => {
+                eprintln!(
+                    "starship-multi-config: warning: {}: not valid UTF-8 (skipping)",
+                    path.display()
+                );
+                false
+            }
+            _ => true,
+        });
+    }
+
+    if paths.is_empty() {
+        // Rather than fail the prompt, globs matching nothing fall back to the default
+        // config as if no arguments were given
+        if unmatched_glob {
+            let defaults = default_sources()?;
+            if !defaults.is_empty() {
+                log::info!("no config files matched, using the default config");
+                return as_configs(defaults);
+            }
+            return Err("no config files matched, and no default config found".into());
+        }
+        return Err("no config files specified".into());
+    }
+
+    if sources.require_first {
+        // Presets, config directories, and includes may come before it
+        let first = first_config.ok_or("--require-first needs a config argument to require")?;
+        if !paths.iter().any(|(path, _)| *path == first) {
+            return Err(path_err(&first, "required first config was skipped").into());
+        }
+        let table = parse_config(&first).map_err(|e| format!("required first config: {e}"))?;
+        if table.is_empty() {
+            return Err(path_err(&first, "required first config is empty").into());
+        }
+    }
+
+    log::info!("resolved {} config files", paths.len());
+    Ok(paths)
+}
+
+/// Canonicalize `paths` and add the files their `include` directives name, see
+/// [`expand_includes`].
+fn expand_all(
+    paths: impl IntoIterator<Item = (PathBuf, SourceKind)>,
+) -> Result<Vec<(PathBuf, SourceKind)>, Box<dyn std::error::Error>> {
+    let mut expanded = Vec::new();
+    let mut kinds = Vec::new();
+    for (path, kind) in paths {
+        // Canonicalize so equivalent spellings of a path share one cache entry. Paths
+        // that can't be resolved are kept as-is and reported when they're read.
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        // Includes go right before the file that names them
+        expand_includes(path, &mut Vec::new(), &mut expanded)?;
+        kinds.resize(expanded.len() - 1, SourceKind::Include);
+        kinds.push(kind);
+    }
+    Ok(expanded.into_iter().zip(kinds).collect())
+}
+
+/// Mark `paths` as plain configs, with their includes.
+fn as_configs(
+    paths: Vec<PathBuf>,
+) -> Result<Vec<(PathBuf, SourceKind)>, Box<dyn std::error::Error>> {
+    expand_all(paths.into_iter().map(|path| (path, SourceKind::Config)))
+}
+
+/// Run `starship <args>` and save its output to a cache file named `<prefix>-<hash>.toml`,
+/// reusing an earlier run unless `no_cache` is set. The cache is keyed on `args`,
+/// `version` if given, and with [`PresetCacheKey::Binary`] the binary's path and mtime.
+/// Without a usable cache directory (see
+/// [`usable_cache_dir`]), starship always runs and its output goes to a temp file.
+fn cached_starship_output(
+    bin_path: &Path,
+    args: &[&str],
+    prefix: &str,
+    no_cache: bool,
+    version: Option<&str>,
+    key: PresetCacheKey,
+    readonly: bool,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let Some(dir) = usable_cache_dir(readonly) else {
+        return write_temp(&run_starship_config(bin_path, args)?);
+    };
+
+    let hash = hash_key(|h| {
+        args.hash(h);
+        version.hash(h);
+        if key == PresetCacheKey::Binary {
+            let bin_mtime = fs::metadata(bin_path)
+                .and_then(|m| m.modified())
+                .map_err(|e| path_err(bin_path, e))?;
+            bin_path.hash(h);
+            bin_mtime.hash(h);
+        }
+        Ok(())
+    })?;
+
+    let mut cache_file = dir.join(format!("{prefix}-{hash}.toml"));
+
+    if no_cache || !cache_file.exists() {
+        cache_file = write_cache(&cache_file, &run_starship_config(bin_path, args)?)?;
+    } else {
+        log::debug!(
+            "cache hit for starship {}: {}",
+            args.join(" "),
+            cache_file.display()
+        );
+    }
+
+    Ok(cache_file)
+}
+
+/// Delete all cached presets if starship's version differs from the one recorded when
+/// they were generated, then record `version`.
+fn refresh_presets(version: &str, readonly: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(dir) = usable_cache_dir(readonly) else {
+        return Ok(());
+    };
+    let stamp = dir.join("preset-version");
+    if fs::read_to_string(&stamp).is_ok_and(|recorded| recorded == version) {
+        return Ok(());
+    }
+
+    log::debug!("starship is now {version}, removing cached presets");
+    for entry in fs::read_dir(&dir).into_iter().flatten() {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with("preset-") && name.ends_with(".toml") {
+            fs::remove_file(&path).map_err(|e| path_err(&path, e))?;
+        }
+    }
+    write_cache(&stamp, version.as_bytes())?;
+    Ok(())
+}
+
+/// Run `starship <args>` and return the config it prints, warning if it's empty. Some
+/// starship builds print nothing for an unknown preset rather than failing.
+fn run_starship_config(
+    bin_path: &Path,
+    args: &[&str],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let output = run_starship(bin_path, args)?;
+    let empty = std::str::from_utf8(&output)
+        .ok()
+        .and_then(|s| s.parse::<toml::Table>().ok())
+        .is_some_and(|table| table.is_empty());
+    if empty {
+        eprintln!(
+            "starship-multi-config: warning: starship {} printed an empty config",
+            args.join(" ")
+        );
+    }
+    Ok(output)
+}
+
+/// Run `starship <args>` and return its stdout.
+pub fn run_starship(bin_path: &Path, args: &[&str]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let command = args.join(" ");
+    log::debug!("running {} {command}", bin_path.display());
+    let output = Command::new(bin_path)
+        .args(args)
+        .output()
+        .map_err(|e| format!("{}: {e}", bin_path.display()))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("starship {command}: {}", stderr.trim()).into());
+    }
+    Ok(output.stdout)
+}
+
+/// Strip leading `env:NAME=VALUE?` conditions from a config argument. Returns the
+/// remaining path if every condition holds, or `None` if the layer should be skipped.
+fn strip_env_conditions(config: &Path) -> Option<&Path> {
+    let Some(mut rest) = config.to_str() else {
+        return Some(config);
+    };
+    while let Some((condition, path)) = rest
+        .strip_prefix("env:")
+        .and_then(|cond| cond.split_once('?'))
+    {
+        let (name, value) = condition.split_once('=')?;
+        if env::var_os(name).is_none_or(|v| v != value) {
+            return None;
+        }
+        rest = path;
+    }
+    Some(Path::new(rest))
+}
+
+/// Split `#key` scopes off a config argument, and locate its path: `~` and variables
+/// are expanded, and a relative path is taken relative to `base`. A path that exists
+/// once located is never split, so file names containing `#` keep working.
+fn split_scopes<'a>(config: &'a Path, base: Option<&Path>) -> (PathBuf, Vec<&'a str>) {
+    let locate = |config: &Path| {
+        let config = expand_path(config);
+        match base {
+            Some(base) if config.is_relative() => base.join(config),
+            _ => config,
+        }
+    };
+    let located = locate(config);
+    let Some(s) = config.to_str().filter(|_| !located.exists()) else {
+        return (located, Vec::new());
+    };
+    let mut parts = s.split('#');
+    let path = parts.next().unwrap_or_default();
+    let scopes: Vec<&str> = parts.filter(|scope| !scope.is_empty()).collect();
+    if scopes.is_empty() {
+        return (located, scopes);
+    }
+    (locate(Path::new(path)), scopes)
+}
+
+/// Expand a config argument containing glob syntax (`*`, `?`, `[...]`, `{a,b}`) into the
+/// files it matches. Brace alternatives expand in order, each globbed and sorted on its
+/// own, with `numeric_sort` ordering file names like `2-x.toml` before `10-x.toml`.
+/// [`GlobSort::Mtime`] then reorders them from oldest to newest.
+/// Other arguments are returned unchanged.
+fn expand_glob(
+    config: PathBuf,
+    numeric_sort: bool,
+    sort: GlobSort,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let Some(pattern) = config.to_str().filter(|p| p.contains(['*', '?', '[', '{'])) else {
+        return Ok(vec![config]);
+    };
+
+    let mut paths = Vec::new();
+    for pattern in expand_braces(pattern) {
+        let mut matches = glob::glob(&pattern)
+            .map_err(|e| format!("{pattern}: {e}"))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            log::debug!("{pattern} matched no files");
+        }
+        if numeric_sort {
+            matches.sort_by(|a, b| {
+                let name = |p: &Path| {
+                    p.file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned()
+                };
+                a.parent()
+                    .cmp(&b.parent())
+                    .then_with(|| natural_cmp(&name(a), &name(b)))
+            });
+        } else {
+            matches.sort();
+        }
+        if sort == GlobSort::Mtime {
+            // `sort_by_cached_key` is stable, so ties keep their name order
+            matches.sort_by_cached_key(|p| fs::metadata(p).and_then(|m| m.modified()).ok());
+        }
+        paths.extend(matches);
+    }
+    Ok(paths)
+}
+
+/// Compare strings with runs of digits ordered by their numeric value, so `2-x` sorts
+/// before `10-x`. Equal numbers with more leading zeros sort later.
+fn natural_cmp(mut a: &str, mut b: &str) -> Ordering {
+    fn split_digits(s: &str) -> (&str, &str) {
+        s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+    }
+
+    loop {
+        match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (a_digits, a_rest) = split_digits(a);
+                let (b_digits, b_rest) = split_digits(b);
+                let a_num = a_digits.trim_start_matches('0');
+                let b_num = b_digits.trim_start_matches('0');
+                let ordering = a_num
+                    .len()
+                    .cmp(&b_num.len())
+                    .then_with(|| a_num.cmp(b_num))
+                    .then_with(|| a_digits.len().cmp(&b_digits.len()));
+                if ordering.is_ne() {
+                    return ordering;
+                }
+                (a, b) = (a_rest, b_rest);
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+            }
+        }
+    }
+}
+
+/// Expand shell-style brace groups, e.g. `{base,theme}/*.toml` into `base/*.toml` and
+/// `theme/*.toml`. Groups may be nested or have empty alternatives; braces without a
+/// top-level comma are kept literally.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let Some(open) = pattern.find('{') else {
+        return vec![pattern.to_string()];
+    };
+    let (prefix, rest) = pattern.split_at(open);
+
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    let mut close = None;
+    for (i, c) in rest.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(i);
+                    break;
+                }
+            }
+            ',' if depth == 1 => commas.push(i),
+            _ => {}
+        }
+    }
+
+    match close {
+        Some(close) if !commas.is_empty() => {
+            let suffix = &rest[close + 1..];
+            let mut start = 1;
+            let mut expanded = Vec::new();
+            for end in commas.into_iter().chain([close]) {
+                let alternative = &rest[start..end];
+                expanded.extend(expand_braces(&format!("{prefix}{alternative}{suffix}")));
+                start = end + 1;
+            }
+            expanded
+        }
+        // Not a brace group: keep the `{` and look for groups after it
+        _ => expand_braces(&rest[1..])
+            .into_iter()
+            .map(|tail| format!("{prefix}{{{tail}"))
+            .collect(),
+    }
+}
+
+/// The file `edit` opens: the last config argument naming a plain file, or Starship's
+/// default config without arguments. Drop-ins, includes, and directories never count.
+pub fn primary_config(sources: &Sources) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    if sources.configs.is_empty() {
+        let config = dirs::home_dir()
+            .ok_or("could not determine home directory")?
+            .join(".config/starship.toml");
+        return Ok(config.is_file().then_some(config));
+    }
+
+    let config_home = sources.config_home.as_deref().map(expand_path);
+    for config in sources.configs.iter().rev() {
+        let Some(config) = strip_env_conditions(config) else {
+            continue;
+        };
+        if config.as_os_str() == "-" || config.to_str().is_some_and(is_url) {
+            continue;
+        }
+        let (config, _) = split_scopes(config, config_home.as_deref());
+        let matches = expand_glob(config, sources.glob_numeric_sort, sources.glob_sort)?;
+        if let Some(file) = matches
+            .into_iter()
+            .rev()
+            .find(|path| path.is_file() && path.extension().is_none_or(|ext| ext != "gz"))
+        {
+            return Ok(Some(file));
+        }
+    }
+    Ok(None)
+}
+
+/// Starship's default config plus any drop-ins in `~/.config/starship.d/`.
+pub fn default_sources() -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let config_dir = dirs::home_dir()
+        .ok_or("could not determine home directory")?
+        .join(".config");
+    let default_config = config_dir.join("starship.toml");
+    let drop_in_dir = config_dir.join("starship.d");
+
+    let mut paths = Vec::new();
+    if default_config.is_file() {
+        paths.push(default_config);
+    }
+    if drop_in_dir.is_dir() {
+        paths.extend(toml_files_in(&drop_in_dir).map_err(|e| path_err(&drop_in_dir, e))?);
+    }
+
+    log::info!("using {} default config files", paths.len());
+    Ok(paths)
+}
+
+/// Drop files inside the `cache` directory. A broad glob like `~/.config/**/*.toml` or
+/// a config directory could otherwise feed merged configs back into the merge, where
+/// they'd compound over time.
+fn skip_cached(paths: Vec<PathBuf>, cache: Option<&Path>) -> Vec<PathBuf> {
+    let Some(cache) = cache else {
+        return paths;
+    };
+    paths
+        .into_iter()
+        .filter(|path| {
+            let cached = fs::canonicalize(path).is_ok_and(|path| path.starts_with(cache));
+            if cached {
+                eprintln!(
+                    "starship-multi-config: warning: {}: skipping generated file in the cache \
+                     directory",
+                    path.display()
+                );
+            }
+            !cached
+        })
+        .collect()
+}
+
+/// How deeply `include` directives may nest, to bound pathological configs.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Add `path` to `paths`, preceded by the files its `# multi: include=<file>` directives
+/// name (recursively), so the including layer overrides them. Includes are relative to
+/// the including file's directory. `stack` is the chain of files including `path`.
+/// Includes already in `paths` are skipped.
+fn expand_includes(
+    path: PathBuf,
+    stack: &mut Vec<PathBuf>,
+    paths: &mut Vec<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let chain = |path: &Path| {
+        stack
+            .iter()
+            .map(|p| p.display().to_string())
+            .chain([path.display().to_string()])
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    };
+    if stack.contains(&path) {
+        return Err(format!("include cycle: {}", chain(&path)).into());
+    }
+    if stack.len() >= MAX_INCLUDE_DEPTH {
+        return Err(format!(
+            "includes nested more than {MAX_INCLUDE_DEPTH} deep: {}",
+            chain(&path)
+        )
+        .into());
+    }
+
+    // Unreadable files are reported when they're merged
+    let content = if !is_toml(&path) {
+        None
+    } else {
+        read_config(&path).ok()
+    };
+    let includes: Vec<PathBuf> = content
+        .iter()
+        .flat_map(|content| directives(content))
+        .filter(|(name, _)| *name == "include")
+        .map(|(_, file)| expand_path(Path::new(file)))
+        .collect();
+    if !includes.is_empty() {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        stack.push(path.clone());
+        for include in includes {
+            let include = dir.join(include);
+            let include = fs::canonicalize(&include).map_err(|e| {
+                format!(
+                    "{} (included from {})",
+                    path_err(&include, e),
+                    path.display()
+                )
+            })?;
+            // A file shared by several layers is merged once, below the first of them,
+            // so it can't override the layers in between
+            if paths.contains(&include) {
+                log::debug!("{}: already included", include.display());
+                continue;
+            }
+            expand_includes(include, stack, paths)?;
+        }
+        stack.pop();
+    }
+    paths.push(path);
+    Ok(())
+}
+
+/// List the `*.toml` files directly inside `dir`, sorted by file name. Files matching a
+/// line of the directory's `disabled` file (names or glob patterns, with `#` comments)
+/// are left out, so drop-ins can be turned off without renaming them.
+fn toml_files_in(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let disabled = match fs::read_to_string(dir.join("disabled")) {
+        Ok(content) => content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| glob::Pattern::new(line).map_err(io::Error::other))
+            .collect::<io::Result<Vec<_>>>()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "toml") && path.is_file() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if disabled.iter().any(|pattern| pattern.matches(&name)) {
+                log::debug!("skipping {}: disabled", path.display());
+                continue;
+            }
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Save stdin to a content-addressed cache file so it can be merged (and hashed) like
+/// any other source file.
+fn resolve_stdin(readonly: bool) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut content = Vec::new();
+    io::stdin()
+        .read_to_end(&mut content)
+        .map_err(|e| format!("stdin: {e}"))?;
+    cache_content("stdin", &content, readonly)
+}
+
+/// Keep only the `scopes` top-level keys of `path`, saved to a content-addressed cache
+/// file, for a `path.toml#key` argument.
+fn resolve_scoped(
+    path: &Path,
+    scopes: &[&str],
+    readonly: bool,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut table = parse_config(path)?;
+    table.retain(|key, _| scopes.contains(&key));
+    for scope in scopes {
+        if !table.contains_key(*scope) {
+            eprintln!(
+                "starship-multi-config: warning: {}: no `{scope}` to merge",
+                path.display()
+            );
+        }
+    }
+    cache_content("scoped", toml::to_string(&table)?.as_bytes(), readonly)
+}
+
+/// Save generated layer content to a cache file named `<prefix>-<hash of content>.toml`.
+fn cache_content(
+    prefix: &str,
+    content: &[u8],
+    readonly: bool,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let Some(dir) = usable_cache_dir(readonly) else {
+        return write_temp(content);
+    };
+
+    let hash = hash_key(|h| {
+        content.hash(h);
+        Ok(())
+    })?;
+
+    let mut cache_file = dir.join(format!("{prefix}-{hash}.toml"));
+
+    if !cache_file.exists() {
+        cache_file = write_cache(&cache_file, content)?;
+    }
+
+    Ok(cache_file)
+}
+
+fn starship_version(bin_path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let output = Command::new(bin_path)
+        .arg("--version")
+        .output()
+        .map_err(|e| format!("{}: {e}", bin_path.display()))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("starship --version: {}", stderr.trim()).into());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brace_expansion() {
+        assert_eq!(expand_braces("{a,b}/*.toml"), ["a/*.toml", "b/*.toml"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand_braces("x{a,b{c,d}}"), ["xa", "xbc", "xbd"]);
+        assert_eq!(
+            expand_braces("conf{,.local}.toml"),
+            ["conf.toml", "conf.local.toml"]
+        );
+        assert_eq!(expand_braces("{a}{b,c}"), ["{a}b", "{a}c"]);
+        assert_eq!(expand_braces("{a,b"), ["{a,b"]);
+    }
+
+    #[test]
+    fn natural_ordering() {
+        let mut names = ["10-x", "2-x", "100-x", "02-x", "b", "a10", "a9"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["2-x", "02-x", "10-x", "100-x", "a9", "a10", "b"]);
+    }
+}
@@ -0,0 +1,45 @@
+//! Trace of merges for diagnosing slow prompts, appended to `trace.log` in the cache
+//! directory.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{Sources, cache::usable_cache_dir};
+
+/// Size at which the trace is moved to `trace.log.old` and a new one started.
+const MAX_TRACE_SIZE: u64 = 1 << 20;
+
+/// Append a line for this run. Tracing must never break the prompt, so failures are
+/// only logged.
+pub fn record(sources: &Sources, paths: &[PathBuf], cache: &str, elapsed: Duration, result: &Path) {
+    let Some(dir) = usable_cache_dir(sources.readonly) else {
+        return;
+    };
+    let trace = dir.join("trace.log");
+    let line = format!(
+        "{} sources={} cache={cache} elapsed={elapsed:?} path={}\n",
+        humantime::format_rfc3339_millis(SystemTime::now()),
+        paths.len(),
+        result.display()
+    );
+    if let Err(e) = append(&trace, &line) {
+        log::debug!("could not write {}: {e}", trace.display());
+    }
+}
+
+fn append(trace: &Path, line: &str) -> std::io::Result<()> {
+    if fs::metadata(trace).is_ok_and(|m| m.len() > MAX_TRACE_SIZE) {
+        fs::rename(trace, trace.with_extension("log.old"))?;
+    }
+    fs::create_dir_all(trace.parent().unwrap_or(Path::new(".")))?;
+    // One write per line, so concurrent prompts don't interleave within a line
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(trace)?
+        .write_all(line.as_bytes())
+}
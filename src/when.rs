@@ -0,0 +1,170 @@
+//! `__when__` conditions that gate whether a layer is merged, e.g.
+//! `__when__ = "env.TERM == 'xterm-kitty' && os != 'windows'"`.
+//!
+//! Values are single- or double-quoted strings, `env.NAME` (empty if unset), `os` (as in
+//! Rust's `std::env::consts::OS`, e.g. `linux` or `macos`), and `host`. They compare
+//! with `==` and `!=`, and comparisons combine with `&&`, `||`, and parentheses.
+
+use std::env;
+
+/// Evaluate a condition, or describe why it's invalid.
+pub fn eval(expr: &str) -> Result<bool, String> {
+    let tokens = tokenize(expr)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let value = parser.or()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(value),
+        Some(token) => Err(format!("unexpected `{token}`")),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Str(String),
+    Ident(String),
+    Eq,
+    Ne,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Str(s) => write!(f, "'{s}'"),
+            Token::Ident(s) => f.write_str(s),
+            Token::Eq => f.write_str("=="),
+            Token::Ne => f.write_str("!="),
+            Token::And => f.write_str("&&"),
+            Token::Or => f.write_str("||"),
+            Token::Open => f.write_str("("),
+            Token::Close => f.write_str(")"),
+        }
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let (token, len) = match c {
+            '\'' | '"' => {
+                let end = rest[1..]
+                    .find(c)
+                    .ok_or_else(|| format!("unterminated string {rest}"))?;
+                (Token::Str(rest[1..end + 1].to_string()), end + 2)
+            }
+            '(' => (Token::Open, 1),
+            ')' => (Token::Close, 1),
+            _ if rest.starts_with("==") => (Token::Eq, 2),
+            _ if rest.starts_with("!=") => (Token::Ne, 2),
+            _ if rest.starts_with("&&") => (Token::And, 2),
+            _ if rest.starts_with("||") => (Token::Or, 2),
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                    .unwrap_or(rest.len());
+                (Token::Ident(rest[..len].to_string()), len)
+            }
+            _ => return Err(format!("unexpected `{c}`")),
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let matched = self.tokens.get(self.pos) == Some(token);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut value = self.and()?;
+        while self.eat(&Token::Or) {
+            // Evaluate both sides so syntax errors aren't hidden by short-circuiting
+            value |= self.and()?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut value = self.comparison()?;
+        while self.eat(&Token::And) {
+            value &= self.comparison()?;
+        }
+        Ok(value)
+    }
+
+    fn comparison(&mut self) -> Result<bool, String> {
+        if self.eat(&Token::Open) {
+            let value = self.or()?;
+            if !self.eat(&Token::Close) {
+                return Err("missing `)`".to_string());
+            }
+            return Ok(value);
+        }
+        let left = self.value()?;
+        let equal = match self.next() {
+            Some(Token::Eq) => true,
+            Some(Token::Ne) => false,
+            Some(token) => return Err(format!("expected `==` or `!=`, found `{token}`")),
+            None => return Err("expected `==` or `!=`".to_string()),
+        };
+        let right = self.value()?;
+        Ok((left == right) == equal)
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(s.clone()),
+            Some(Token::Ident(name)) => match name.as_str() {
+                "os" => Ok(env::consts::OS.to_string()),
+                "host" => Ok(gethostname::gethostname().to_string_lossy().into_owned()),
+                _ => match name.strip_prefix("env.") {
+                    Some(var) if !var.is_empty() => Ok(env::var(var).unwrap_or_default()),
+                    _ => Err(format!("unknown value `{name}`")),
+                },
+            },
+            Some(token) => Err(format!("expected a value, found `{token}`")),
+            None => Err("expected a value".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_conditions() {
+        let os = env::consts::OS;
+        assert_eq!(eval(&format!("os == '{os}'")), Ok(true));
+        assert_eq!(eval(&format!("os != \"{os}\"")), Ok(false));
+        assert_eq!(eval("env.STARSHIP_MULTI_UNSET_VAR == ''"), Ok(true));
+        assert_eq!(eval("'a' == 'b' || ('a' == 'a' && 'b' != 'c')"), Ok(true));
+        assert_eq!(eval("'a' == 'a' && 'b' == 'c'"), Ok(false));
+
+        assert_eq!(eval("term == 'x'"), Err("unknown value `term`".to_string()));
+        assert_eq!(eval("'a' = 'a'"), Err("unexpected `=`".to_string()));
+        assert_eq!(eval("('a' == 'a'"), Err("missing `)`".to_string()));
+        assert_eq!(eval("'a' == 'a' 'b'"), Err("unexpected `'b'`".to_string()));
+    }
+}
@@ -1,12 +1,22 @@
 use std::env;
 use std::fs;
-use std::os::unix::fs::PermissionsExt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::{PermissionsExt, symlink};
 use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+use flate2::{Compression, write::GzEncoder};
 use tempfile::TempDir;
 
 fn cmd() -> assert_cmd::Command {
-    assert_cmd::cargo::cargo_bin_cmd!("starship-multi-config")
+    let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("starship-multi-config");
+    // Keep cached output free of temp paths and timestamps so it can be snapshotted
+    cmd.env("STARSHIP_MULTI_NO_HEADER", "1");
+    cmd
 }
 
 fn write_toml(dir: &TempDir, name: &str, content: &str) -> String {
@@ -16,7 +26,11 @@ fn write_toml(dir: &TempDir, name: &str, content: &str) -> String {
 }
 
 /// Creates a fake `starship` binary in the given directory that handles
-/// `preset <name>` calls by outputting TOML content from a matching file.
+/// `preset <name>` calls by outputting TOML content from a matching file (and
+/// `preset --list` by listing them), and
+/// `--version` by outputting the `version` file in the directory, and
+/// `print-config --default` by outputting its `default.toml` file.
+/// Each invocation's arguments are appended to `starship.log` in the directory.
 /// Returns a PATH string with the stub directory prepended.
 fn write_starship_stub(dir: &TempDir, presets: &[(&str, &str)]) -> String {
     let presets_dir = dir.path().join("presets");
@@ -25,9 +39,23 @@ fn write_starship_stub(dir: &TempDir, presets: &[(&str, &str)]) -> String {
         fs::write(presets_dir.join(format!("{name}.toml")), content).unwrap();
     }
     let path = dir.path().join("starship");
+    fs::write(dir.path().join("version"), "starship 1.0.0\n").unwrap();
     let script = format!(
-        "#!/bin/sh\nif [ \"$1\" = \"preset\" ]; then\n  cat \"{}/\"\"$2\".toml\nelse\n  echo \"unexpected args: $@\" >&2\n  exit 1\nfi\n",
-        presets_dir.display()
+        r#"#!/bin/sh
+echo "$@" >> "{dir}/starship.log"
+case "$1" in
+  preset)
+    if [ "$2" = --list ]; then ls "{presets}" | sed 's/\.toml$//'
+    else cat "{presets}/$2.toml"; fi ;;
+  --version) cat "{dir}/version" ;;
+  print-config)
+    if [ "$2" = --default ]; then cat "{dir}/default.toml"
+    else echo "unexpected args: $@" >&2; exit 1; fi ;;
+  *) echo "unexpected args: $@" >&2; exit 1 ;;
+esac
+"#,
+        dir = dir.path().display(),
+        presets = presets_dir.display()
     );
     fs::write(&path, script).unwrap();
     fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
@@ -36,65 +64,3225 @@ fn write_starship_stub(dir: &TempDir, presets: &[(&str, &str)]) -> String {
     format!("{stub_dir}:{system_path}")
 }
 
+/// Serves `body` to every request on a local port. Returns the URL, the number of
+/// requests served so far, and the response status, which can be changed to simulate
+/// a failing server.
+fn serve_http(body: &'static str) -> (String, Arc<AtomicUsize>, Arc<AtomicU16>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/starship.toml", listener.local_addr().unwrap());
+    let requests = Arc::new(AtomicUsize::new(0));
+    let status = Arc::new(AtomicU16::new(200));
+    let (served, current) = (requests.clone(), status.clone());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            served.fetch_add(1, Ordering::SeqCst);
+            let status = current.load(Ordering::SeqCst);
+            let body = if status == 200 { body } else { "" };
+            let response = format!(
+                "HTTP/1.1 {status} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (url, requests, status)
+}
+
+#[test]
+fn single_config_passthrough() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+
+    let expected = fs::canonicalize(&f1).unwrap();
+    cmd()
+        .arg(&f1)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", expected.display()));
+}
+
+#[test]
+fn relative_and_absolute_paths_share_cache() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    let absolute = cmd()
+        .args([&f1, &f2])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let relative = cmd()
+        .current_dir(dir.path())
+        .args(["./base.toml", "override.toml"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    assert_eq!(
+        String::from_utf8(absolute).unwrap(),
+        String::from_utf8(relative).unwrap()
+    );
+}
+
+#[test]
+fn merge_two_files() {
+    let dir = TempDir::new().unwrap();
+
+    let f1 = write_toml(
+        &dir,
+        "base.toml",
+        r#"
+format = "$all"
+
+[character]
+success_symbol = "[>](bold green)"
+error_symbol = "[>](bold red)"
+"#,
+    );
+
+    let f2 = write_toml(
+        &dir,
+        "override.toml",
+        r#"
+[character]
+success_symbol = "[→](bold cyan)"
+
+[package]
+disabled = true
+"#,
+    );
+
+    let output = cmd()
+        .args([&f1, &f2])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cache_path = stdout.trim();
+
+    assert!(
+        Path::new(cache_path).exists(),
+        "cache file should exist at {cache_path}"
+    );
+
+    let cached_toml = fs::read_to_string(cache_path).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
+#[test]
+fn crlf_sources_merge_to_lf() {
+    let dir = TempDir::new().unwrap();
+    let windows = write_toml(
+        &dir,
+        "windows.toml",
+        "format = \"\"\"\r\n$directory\r\n$character\"\"\"\r\n\r\n[character]\r\nsuccess_symbol = \">\"\r\n",
+    );
+    let unix = write_toml(&dir, "unix.toml", "add_newline = false\n");
+
+    let output = cmd()
+        .args([&windows, &unix])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert!(!cached_toml.contains('\r'), "{cached_toml:?}");
+    assert!(
+        cached_toml.contains("$directory\n$character"),
+        "{cached_toml}"
+    );
+}
+
+#[test]
+fn merge_gzip_layer() {
+    let dir = TempDir::new().unwrap();
+
+    let base = dir.path().join("base.toml.gz");
+    let mut encoder = GzEncoder::new(fs::File::create(&base).unwrap(), Compression::default());
+    encoder
+        .write_all(
+            br#"
+format = "$all"
+
+[character]
+success_symbol = "[>](bold green)"
+error_symbol = "[>](bold red)"
+"#,
+        )
+        .unwrap();
+    encoder.finish().unwrap();
+
+    let f2 = write_toml(
+        &dir,
+        "override.toml",
+        r#"
+[character]
+success_symbol = "[→](bold cyan)"
+"#,
+    );
+
+    let output = cmd()
+        .args([base.to_str().unwrap(), &f2])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
+#[test]
+fn single_gzip_config_is_decompressed() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.path().join("base.toml.gz");
+    let mut encoder = GzEncoder::new(fs::File::create(&config).unwrap(), Compression::default());
+    encoder.write_all(b"format = \"$all\"\n").unwrap();
+    encoder.finish().unwrap();
+
+    let output = cmd()
+        .arg(&config)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    assert!(stdout.trim().ends_with(".toml"), "{stdout}");
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "format = \"$all\"\n"
+    );
+}
+
+#[test]
+fn json_null_deletes_key() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        r#"
+format = "$all"
+
+[character]
+success_symbol = "[>](bold green)"
+error_symbol = "[x](bold red)"
+"#,
+    );
+    let json = write_toml(
+        &dir,
+        "override.json",
+        r#"{"add_newline": false, "format": null, "character": {"error_symbol": null}}"#,
+    );
+
+    let output = cmd()
+        .args([&base, &json])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(
+        cached_toml,
+        "add_newline = false\n\n[character]\nsuccess_symbol = \"[>](bold green)\"\n"
+    );
+}
+
+#[test]
+fn ini_layer_merges_over_toml() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        r#"
+add_newline = true
+
+[character]
+success_symbol = "[>](bold green)"
+error_symbol = "[x](bold red)"
+"#,
+    );
+    let ini = write_toml(
+        &dir,
+        "override.ini",
+        "; local tweaks\nadd_newline = false\n\n[character]\nsuccess_symbol = \"[>](bold blue)\"\n",
+    );
+
+    let output = cmd()
+        .args([&base, &ini])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(
+        cached_toml,
+        "add_newline = false\n\n[character]\nerror_symbol = \"[x](bold red)\"\n\
+         success_symbol = \"[>](bold blue)\"\n"
+    );
+}
+
+#[test]
+fn set_overrides_every_layer() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        "[character]\nsuccess_symbol = \"[>](bold green)\"\nerror_symbol = \"[x](bold red)\"\n",
+    );
+
+    let merge = |sets: &[&str]| {
+        let mut cmd = cmd();
+        for set in sets {
+            cmd.args(["--set", set]);
+        }
+        let output = cmd
+            .arg(&base)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let stdout = String::from_utf8(output).unwrap();
+        (stdout.clone(), fs::read_to_string(stdout.trim()).unwrap())
+    };
+
+    let (path, merged) = merge(&[
+        "character.success_symbol=\"[✓](green)\"",
+        "add_newline=false",
+    ]);
+    assert_eq!(
+        merged,
+        "add_newline = false\n\n[character]\nerror_symbol = \"[x](bold red)\"\n\
+         success_symbol = \"[✓](green)\"\n"
+    );
+
+    // Different overrides get their own cache entry
+    let (other_path, merged) = merge(&["character.success_symbol='[>](blue)'"]);
+    assert_ne!(path, other_path);
+    assert!(merged.contains("success_symbol = \"[>](blue)\""));
+
+    // Overrides go through the same final pass as the layers
+    let vars = write_toml(&dir, "vars.toml", "[__vars__]\nsymbol = \"[$](red)\"\n");
+    let output = cmd()
+        .args([
+            "--set",
+            "__priority__=1",
+            "--set",
+            "character.success_symbol=\"$__vars__.symbol\"",
+        ])
+        .args([&base, &vars])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "[character]\nerror_symbol = \"[x](bold red)\"\nsuccess_symbol = \"[$](red)\"\n"
+    );
+
+    cmd()
+        .args(["--set", "character.success_symbol=[>](blue)", &base])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::starts_with(
+            "starship-multi-config: --set character.success_symbol=[>](blue): invalid value",
+        ));
+}
+
+#[test]
+fn single_json_config_is_converted() {
+    let dir = TempDir::new().unwrap();
+    let json = write_toml(
+        &dir,
+        "config.json",
+        r#"{"add_newline": false, "format": null}"#,
+    );
+
+    let output = cmd()
+        .arg(&json)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    assert!(stdout.trim().ends_with(".toml"), "{stdout}");
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\n"
+    );
+}
+
+#[test]
+fn single_ini_config_is_converted() {
+    let dir = TempDir::new().unwrap();
+    let ini = write_toml(
+        &dir,
+        "starship.conf",
+        "add_newline = false\n\n[character]\nsuccess_symbol = >\n",
+    );
+
+    let output = cmd()
+        .arg(&ini)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    assert!(stdout.trim().ends_with(".toml"), "{stdout}");
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\n\n[character]\nsuccess_symbol = \">\"\n"
+    );
+}
+
+#[test]
+fn remote_config_is_cached_with_offline_fallback() {
+    let dir = TempDir::new().unwrap();
+    let cache_dir = dir.path().join("cache");
+    let local = write_toml(&dir, "local.toml", "add_newline = false\n");
+    let (url, requests, status) = serve_http("format = \"$all\"\n");
+
+    let merge = |ttl: &str| {
+        let output = cmd()
+            .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+            .env("STARSHIP_MULTI_TEST_ALLOW_HTTP", "1")
+            .env("STARSHIP_REMOTE_TTL", ttl)
+            .args([&url, &local])
+            .assert()
+            .success()
+            .get_output()
+            .clone();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let merged = fs::read_to_string(stdout.trim()).unwrap();
+        (merged, String::from_utf8(output.stderr).unwrap())
+    };
+
+    // Plain HTTP isn't fetched at all outside of tests
+    cmd()
+        .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+        .args([&url, &local])
+        .assert()
+        .code(1);
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+    let expected = "add_newline = false\nformat = \"$all\"\n";
+    assert_eq!(merge("1h").0, expected);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // Within the TTL the cached copy is used without a request
+    assert_eq!(merge("1h").0, expected);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // Once it expires, a failed fetch falls back to the cached copy
+    status.store(503, Ordering::SeqCst);
+    let (merged, stderr) = merge("0s");
+    assert_eq!(merged, expected);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    assert!(stderr.contains("using the cached copy"), "{stderr}");
+}
+
+#[test]
+fn git_config_is_fetched_at_ref() {
+    let dir = TempDir::new().unwrap();
+    let cache_dir = dir.path().join("cache");
+    let repo = dir.path().join("dotfiles");
+    let local = write_toml(&dir, "local.toml", "add_newline = false\n");
+
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .arg("-C")
+            .arg(&repo)
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {args:?}");
+    };
+    fs::create_dir_all(repo.join("starship")).unwrap();
+    git(&["init", "--quiet"]);
+    fs::write(repo.join("starship/base.toml"), "format = \"$all\"\n").unwrap();
+    git(&["add", "."]);
+    git(&["commit", "--quiet", "-m", "v1"]);
+    git(&["tag", "v1"]);
+    fs::write(repo.join("starship/base.toml"), "format = \"$directory\"\n").unwrap();
+    git(&["commit", "--quiet", "-am", "v2"]);
+
+    let merge = |url: String, ttl: &str| {
+        let output = cmd()
+            .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+            .env("STARSHIP_MULTI_TEST_ALLOW_HTTP", "1")
+            .env("STARSHIP_REMOTE_TTL", ttl)
+            .args([&url, &local])
+            .assert()
+            .success()
+            .get_output()
+            .clone();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let merged = fs::read_to_string(stdout.trim()).unwrap();
+        (merged, String::from_utf8(output.stderr).unwrap())
+    };
+    let url = |rev: &str| format!("git+file://{}#starship/base.toml{rev}", repo.display());
+
+    assert_eq!(
+        merge(url("@v1"), "1h").0,
+        "add_newline = false\nformat = \"$all\"\n"
+    );
+    assert_eq!(
+        merge(url(""), "1h").0,
+        "add_newline = false\nformat = \"$directory\"\n"
+    );
+
+    // Once the cached copy expires, an unreachable repository falls back to it
+    fs::remove_dir_all(&repo).unwrap();
+    let (merged, stderr) = merge(url("@v1"), "0s");
+    assert_eq!(merged, "add_newline = false\nformat = \"$all\"\n");
+    assert!(stderr.contains("using the cached copy"), "{stderr}");
+}
+
+#[test]
+fn merge_stdin_layer() {
+    let dir = TempDir::new().unwrap();
+
+    let f1 = write_toml(
+        &dir,
+        "base.toml",
+        r#"
+format = "$all"
+
+[character]
+success_symbol = "[>](bold green)"
+error_symbol = "[>](bold red)"
+"#,
+    );
+
+    let output = cmd()
+        .args([&f1, "-"])
+        .write_stdin(
+            r#"
+[character]
+success_symbol = "[→](bold cyan)"
+"#,
+        )
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
+#[test]
+fn brace_glob_expands_in_order() {
+    let dir = TempDir::new().unwrap();
+    fs::create_dir_all(dir.path().join("base")).unwrap();
+    fs::create_dir_all(dir.path().join("theme")).unwrap();
+    write_toml(&dir, "base/10-format.toml", "format = \"$all\"\n");
+    write_toml(
+        &dir,
+        "base/20-char.toml",
+        "[character]\nsuccess_symbol = \">\"\n",
+    );
+    write_toml(
+        &dir,
+        "theme/char.toml",
+        "[character]\nsuccess_symbol = \"→\"\n",
+    );
+
+    let output = cmd()
+        .current_dir(dir.path())
+        .arg("{base,theme}/*.toml")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
+#[test]
+fn glob_skips_files_in_cache_dir() {
+    let dir = TempDir::new().unwrap();
+    write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    write_toml(&dir, "override.toml", "add_newline = false\n");
+    fs::create_dir(dir.path().join("cache")).unwrap();
+    write_toml(&dir, "cache/0123abcd.toml", "format = \"cached\"\n");
+
+    let output = cmd()
+        .current_dir(dir.path())
+        .env("STARSHIP_MULTI_CACHE_DIR", dir.path().join("cache"))
+        .args(["explain", "**/*.toml"])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "0123abcd.toml: skipping generated file in the cache directory",
+        ))
+        .get_output()
+        .stdout
+        .clone();
+
+    let explained = String::from_utf8(output).unwrap();
+    assert!(explained.contains("format = \"$all\""), "{explained}");
+}
+
+#[test]
+fn glob_numeric_sort() {
+    let dir = TempDir::new().unwrap();
+    for prefix in ["2", "10", "100"] {
+        write_toml(
+            &dir,
+            &format!("{prefix}-layer.toml"),
+            &format!("format = \"{prefix}\"\n"),
+        );
+    }
+
+    let merge = |numeric: &str| {
+        let output = cmd()
+            .current_dir(dir.path())
+            .env("STARSHIP_GLOB_NUMERIC_SORT", numeric)
+            .args(["explain", "*.toml"])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(output).unwrap()
+    };
+
+    // The last layer wins: `2-` lexically, `100-` numerically
+    assert!(merge("0").contains("format = \"2\""));
+    assert!(merge("1").contains("format = \"100\""));
+}
+
+#[test]
+fn glob_mtime_sort() {
+    let dir = TempDir::new().unwrap();
+    let now = SystemTime::now();
+    for (name, age) in [("a-newest", 0), ("b-oldest", 120), ("c-older", 60)] {
+        let path = write_toml(
+            &dir,
+            &format!("{name}.toml"),
+            &format!("format = \"{name}\"\n"),
+        );
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(now - Duration::from_secs(age))
+            .unwrap();
+    }
+
+    let merge = |sort: &str| {
+        let output = cmd()
+            .current_dir(dir.path())
+            .env("STARSHIP_GLOB_SORT", sort)
+            .args(["explain", "*.toml"])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(output).unwrap()
+    };
+
+    // The last layer wins: `c-` by name, the most recently modified by mtime
+    assert!(merge("name").contains("format = \"c-older\""));
+    assert!(merge("mtime").contains("format = \"a-newest\""));
+}
+
+#[test]
+fn config_dirs_merge_in_listed_order() {
+    let dir = TempDir::new().unwrap();
+    let team = dir.path().join("team");
+    let personal = dir.path().join("personal");
+    fs::create_dir(&team).unwrap();
+    fs::create_dir(&personal).unwrap();
+    fs::write(
+        team.join("10-format.toml"),
+        "format = \"$all\"\n\n[character]\nsuccess_symbol = \">\"\n",
+    )
+    .unwrap();
+    fs::write(
+        team.join("20-git.toml"),
+        "[git_branch]\nstyle = \"bold purple\"\n",
+    )
+    .unwrap();
+    fs::write(
+        personal.join("character.toml"),
+        "[character]\nsuccess_symbol = \"→\"\n",
+    )
+    .unwrap();
+    let config = write_toml(&dir, "config.toml", "add_newline = false\n");
+
+    let output = cmd()
+        .env(
+            "STARSHIP_CONFIG_DIR",
+            env::join_paths([&team, &personal]).unwrap(),
+        )
+        .arg(&config)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
+#[test]
+fn config_dir_argument_expands_to_toml_files() {
+    let dir = TempDir::new().unwrap();
+    let layers = dir.path().join("starship.d");
+    fs::create_dir(&layers).unwrap();
+    fs::write(layers.join("20-override.toml"), "add_newline = false\n").unwrap();
+    fs::write(
+        layers.join("10-base.toml"),
+        "format = \"$all\"\nadd_newline = true\n",
+    )
+    .unwrap();
+    fs::write(layers.join("README.md"), "# not a config\n").unwrap();
+    let extra = write_toml(&dir, "extra.toml", "[character]\nsuccess_symbol = \">\"\n");
+
+    let output = cmd()
+        .env("STARSHIP_MULTI_NO_HEADER", "1")
+        .args([layers.to_str().unwrap(), &extra])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(
+        cached_toml,
+        "add_newline = false\nformat = \"$all\"\n\n[character]\nsuccess_symbol = \">\"\n"
+    );
+}
+
+#[test]
+fn disabled_file_skips_drop_ins() {
+    let dir = TempDir::new().unwrap();
+    let conf = dir.path().join("conf.d");
+    fs::create_dir(&conf).unwrap();
+    fs::write(conf.join("10-format.toml"), "format = \"$all\"\n").unwrap();
+    fs::write(conf.join("20-git.toml"), "[git_branch]\ndisabled = true\n").unwrap();
+    fs::write(conf.join("30-newline.toml"), "add_newline = false\n").unwrap();
+    fs::write(conf.join("disabled"), "# Too noisy for now\n20-*.toml\n").unwrap();
+
+    let output = cmd()
+        .env("STARSHIP_CONFIG_DIR", &conf)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(cached_toml, "add_newline = false\nformat = \"$all\"\n");
+}
+
+#[test]
+fn config_max_limits_glob_expansion() {
+    let dir = TempDir::new().unwrap();
+    for i in 0..4 {
+        write_toml(&dir, &format!("{i}.toml"), "format = \"$all\"\n");
+    }
+
+    cmd()
+        .current_dir(dir.path())
+        .env("STARSHIP_MULTI_CONFIG_MAX", "3")
+        .arg("*.toml")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "too many config files (4, limit 3); raise STARSHIP_MULTI_CONFIG_MAX",
+        ));
+
+    // Includes count too
+    let base = write_toml(&dir, "base.toml", "# multi: include=0.toml\n");
+    cmd()
+        .env("STARSHIP_MULTI_CONFIG_MAX", "1")
+        .arg(&base)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "too many config files (2, limit 1)",
+        ));
+
+    // So do config directories
+    let conf = TempDir::new().unwrap();
+    for i in 0..5 {
+        write_toml(&conf, &format!("{i}.toml"), "");
+    }
+    cmd()
+        .env("STARSHIP_MULTI_CONFIG_MAX", "2")
+        .args(["--config-dir", conf.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "too many config files (5, limit 2)",
+        ));
+
+    // And the default config's drop-ins
+    let home = TempDir::new().unwrap();
+    fs::create_dir_all(home.path().join(".config/starship.d")).unwrap();
+    for i in 0..4 {
+        write_toml(&home, &format!(".config/starship.d/{i}.toml"), "");
+    }
+    cmd()
+        .env("HOME", home.path())
+        .env("STARSHIP_MULTI_CONFIG_MAX", "3")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "too many config files (4, limit 3)",
+        ));
+}
+
+#[test]
+fn vars_substituted_across_layers() {
+    let dir = TempDir::new().unwrap();
+
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        r##"
+[__vars__]
+accent = "#ff79c6"
+width = 40
+
+[git_branch]
+style = "bold $__vars__.accent"
+truncation_length = "$__vars__.width"
+"##,
+    );
+    let override_ = write_toml(
+        &dir,
+        "override.toml",
+        r##"
+[__vars__]
+accent = "#50fa7b"
+
+[character]
+success_symbol = "[>]($__vars__.accent)"
+"##,
+    );
+
+    let output = cmd()
+        .args([&base, &override_])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
+#[test]
+fn priority_overrides_layer_order() {
+    let dir = TempDir::new().unwrap();
+    let high = write_toml(
+        &dir,
+        "a-high.toml",
+        "__priority__ = 10\nformat = \"$character\"\n",
+    );
+    let low = write_toml(
+        &dir,
+        "b-low.toml",
+        "format = \"$all\"\nadd_newline = false\n",
+    );
+
+    let output = cmd()
+        .args([&high, &low])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\nformat = \"$character\"\n"
+    );
+}
+
+#[test]
+fn when_condition_gates_layer() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let kitty = write_toml(
+        &dir,
+        "kitty.toml",
+        "__when__ = \"env.TERM == 'xterm-kitty' || os == 'plan9'\"\nadd_newline = false\n",
+    );
+
+    for (term, expected) in [
+        ("xterm-kitty", "add_newline = false\nformat = \"$all\"\n"),
+        ("xterm-256color", "format = \"$all\"\n"),
+    ] {
+        let output = cmd()
+            .env("TERM", term)
+            .env("STARSHIP_MULTI_CACHE_ENV", "TERM")
+            .args([&base, &kitty])
+            .assert()
+            .success()
+            .stderr("")
+            .get_output()
+            .stdout
+            .clone();
+        let stdout = String::from_utf8(output).unwrap();
+        assert_eq!(fs::read_to_string(stdout.trim()).unwrap(), expected);
+    }
+}
+
+#[test]
+fn prune_unreferenced_palettes() {
+    let dir = TempDir::new().unwrap();
+    let preset = write_toml(
+        &dir,
+        "preset.toml",
+        "palette = \"catppuccin\"\n\n[palettes.catppuccin]\nred = \"#f38ba8\"\n",
+    );
+    let config = write_toml(
+        &dir,
+        "config.toml",
+        "palette = \"mine\"\n\n[palettes.mine]\nred = \"#ff0000\"\n",
+    );
+
+    let merge = |prune: &str| {
+        let output = cmd()
+            .env("STARSHIP_MULTI_PRUNE_PALETTES", prune)
+            .args([&preset, &config])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let stdout = String::from_utf8(output).unwrap();
+        fs::read_to_string(stdout.trim()).unwrap()
+    };
+
+    assert_eq!(
+        merge("0"),
+        "palette = \"mine\"\n\n[palettes.catppuccin]\nred = \"#f38ba8\"\n\n\
+         [palettes.mine]\nred = \"#ff0000\"\n"
+    );
+    assert_eq!(
+        merge("1"),
+        "palette = \"mine\"\n\n[palettes.mine]\nred = \"#ff0000\"\n"
+    );
+
+    // Also when there's only one source
+    let both = write_toml(
+        &dir,
+        "both.toml",
+        "palette = \"mine\"\n\n[palettes.catppuccin]\nred = \"#f38ba8\"\n\n\
+         [palettes.mine]\nred = \"#ff0000\"\n",
+    );
+    let output = cmd()
+        .env("STARSHIP_MULTI_PRUNE_PALETTES", "1")
+        .arg(&both)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "palette = \"mine\"\n\n[palettes.mine]\nred = \"#ff0000\"\n"
+    );
+}
+
+#[test]
+fn append_unique_arrays_dedupe() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        "[custom.tool]\ndetect_files = [\"a.json\", \"b.json\", \"a.json\"]\n",
+    );
+    let extra = write_toml(
+        &dir,
+        "extra.toml",
+        "__arrays__ = \"append-unique\"\n\n\
+         [custom.tool]\ndetect_files = [\"b.json\", \"c.json\"]\n",
+    );
+
+    let output = cmd()
+        .args([&base, &extra])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "[custom.tool]\ndetect_files = [\"a.json\", \"b.json\", \"c.json\"]\n"
+    );
+}
+
+#[test]
+fn include_directive_merges_below_layer() {
+    let dir = TempDir::new().unwrap();
+    write_toml(&dir, "base.toml", "format = \"$all\"\nadd_newline = true\n");
+    let layer = write_toml(
+        &dir,
+        "layer.toml",
+        "# multi: include=base.toml\nadd_newline = false\n",
+    );
+    let other = write_toml(&dir, "other.toml", "[character]\nsuccess_symbol = \">\"\n");
+
+    let output = cmd()
+        .args([&layer, &other])
+        .assert()
+        .success()
+        .stderr("")
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(
+        cached_toml,
+        "add_newline = false\nformat = \"$all\"\n\n[character]\nsuccess_symbol = \">\"\n"
+    );
+}
+
+#[test]
+fn includes_resolve_relative_to_including_file() {
+    let dir = TempDir::new().unwrap();
+    let write = |name: &str, content: &str| {
+        let path = dir.path().join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+    };
+    // Reached through a config argument, then a nested include in another tree
+    write("home/layer.toml", "# multi: include=../shared/base.toml\n");
+    write(
+        "shared/base.toml",
+        "# multi: include=colors/palette.toml\nformat = \"$all\"\n",
+    );
+    write("shared/colors/palette.toml", "palette = \"mine\"\n");
+    // Reached through a config directory
+    write("drop-ins/10-work.toml", "# multi: include=work/base.toml\n");
+    write("drop-ins/work/base.toml", "add_newline = false\n");
+
+    let output = cmd()
+        .current_dir(dir.path().join("shared/colors"))
+        .env("STARSHIP_CONFIG_DIR", dir.path().join("drop-ins"))
+        .env("STARSHIP_MULTI_NO_HEADER", "1")
+        .arg(dir.path().join("home/layer.toml"))
+        .assert()
+        .success()
+        .stderr("")
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\nformat = \"$all\"\npalette = \"mine\"\n"
+    );
+}
+
+#[test]
+fn shared_include_is_merged_once() {
+    let dir = TempDir::new().unwrap();
+    write_toml(
+        &dir,
+        "common.toml",
+        "format = \"common\"\nadd_newline = true\n",
+    );
+    let a = write_toml(
+        &dir,
+        "a.toml",
+        "# multi: include=common.toml\nformat = \"a\"\n",
+    );
+    let b = write_toml(
+        &dir,
+        "b.toml",
+        "# multi: include=common.toml\nadd_newline = false\n",
+    );
+
+    let output = cmd()
+        .args([&a, &b])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\nformat = \"a\"\n"
+    );
+}
+
+#[test]
+fn default_config_includes_are_merged() {
+    let home = TempDir::new().unwrap();
+    fs::create_dir(home.path().join(".config")).unwrap();
+    write_toml(&home, ".config/inc.toml", "format = \"$all\"\n");
+    write_toml(
+        &home,
+        ".config/starship.toml",
+        "# multi: include=inc.toml\nadd_newline = false\n",
+    );
+
+    // Without arguments, and as the fallback for globs that match nothing
+    for args in [vec![], vec!["conf.d/*.toml"]] {
+        let output = cmd()
+            .env("HOME", home.path())
+            .current_dir(home.path())
+            .args(args)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let stdout = String::from_utf8(output).unwrap();
+        assert_eq!(
+            fs::read_to_string(stdout.trim()).unwrap(),
+            "add_newline = false\nformat = \"$all\"\n"
+        );
+    }
+}
+
+#[test]
+fn include_cycle_reports_chain() {
+    let dir = TempDir::new().unwrap();
+    let a = write_toml(&dir, "a.toml", "# multi: include=b.toml\n");
+    write_toml(&dir, "b.toml", "# multi: include=c.toml\n");
+    write_toml(
+        &dir,
+        "c.toml",
+        "# multi: include=a.toml\nformat = \"$all\"\n",
+    );
+    let [a_path, b_path, c_path] = ["a", "b", "c"]
+        .map(|name| fs::canonicalize(dir.path().join(format!("{name}.toml"))).unwrap());
+
+    cmd().args([&a, &a]).assert().code(1).stderr(format!(
+        "starship-multi-config: include cycle: {} -> {} -> {} -> {}\n",
+        a_path.display(),
+        b_path.display(),
+        c_path.display(),
+        a_path.display()
+    ));
+}
+
+#[test]
+fn fill_only_layer_adds_missing_keys() {
+    let dir = TempDir::new().unwrap();
+    let config = write_toml(
+        &dir,
+        "config.toml",
+        "add_newline = false\n\n[character]\nsuccess_symbol = \">\"\n",
+    );
+    let defaults = write_toml(
+        &dir,
+        "defaults.toml",
+        "__fill_only__ = true\nadd_newline = true\nformat = \"$all\"\n\n\
+         [character]\nsuccess_symbol = \"❯\"\nerror_symbol = \"x\"\n",
+    );
+
+    let output = cmd()
+        .args([&config, &defaults])
+        .assert()
+        .success()
+        .stderr("")
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n\n\
+         [character]\nerror_symbol = \"x\"\nsuccess_symbol = \">\"\n"
+    );
+}
+
+#[test]
+fn reserved_keys_never_reach_output() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        "__arrays__ = \"append\"\n__fill_only__ = false\n__priority__ = 1\n\
+         __when__ = \"'a' == 'a'\"\nformat = \"$all\"\n\n\
+         [__vars__]\ngreen = \"bold green\"\n\n\
+         [character]\n__priority__ = 5\n__when__ = \"os == 'none'\"\n\
+         success_symbol = \"[>]($__vars__.green)\"\n\n\
+         [custom.tool]\n__arrays__ = \"replace\"\ndetect_files = [\"a.json\", \"__delete__\"]\n\n\
+         [custom.tool.__vars__]\nunused = 1\n",
+    );
+    let over = write_toml(
+        &dir,
+        "over.toml",
+        "__fill_only__ = true\n\n[character]\nerror_symbol = \"__delete__\"\n",
+    );
+
+    // A single file is merged too rather than passed through with its reserved keys
+    for args in [vec![&base, &over], vec![&base]] {
+        let output = cmd()
+            .args(args)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let stdout = String::from_utf8(output).unwrap();
+        assert_eq!(
+            fs::read_to_string(stdout.trim()).unwrap(),
+            "format = \"$all\"\n\n[character]\nsuccess_symbol = \"[>](bold green)\"\n\n\
+             [custom.tool]\ndetect_files = [\"a.json\"]\n"
+        );
+    }
+
+    let directive = write_toml(
+        &dir,
+        "directive.toml",
+        "# multi: priority=5\nformat = \"$all\"\n",
+    );
+    let output = cmd()
+        .arg(&directive)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert_ne!(
+        stdout.trim(),
+        fs::canonicalize(&directive).unwrap().to_str().unwrap()
+    );
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "format = \"$all\"\n"
+    );
+}
+
+#[test]
+fn directive_comment_appends_arrays() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        "[[battery.display]]\nthreshold = 10\nstyle = \"bold red\"\n",
+    );
+    let extra = write_toml(
+        &dir,
+        "extra.toml",
+        "# multi: arrays=append\n# Also show the battery when it's getting low\n\n\
+         [[battery.display]]\nthreshold = 30\nstyle = \"bold yellow\"\n",
+    );
+    let replace = write_toml(
+        &dir,
+        "replace.toml",
+        "[[battery.display]]\nthreshold = 50\n",
+    );
+
+    let merge = |args: &[&String]| {
+        let output = cmd()
+            .args(args)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let stdout = String::from_utf8(output).unwrap();
+        fs::read_to_string(stdout.trim()).unwrap()
+    };
+
+    assert_eq!(
+        merge(&[&base, &extra]),
+        "[[battery.display]]\nstyle = \"bold red\"\nthreshold = 10\n\n\
+         [[battery.display]]\nstyle = \"bold yellow\"\nthreshold = 30\n"
+    );
+    // Without the directive, arrays are replaced as usual
+    assert_eq!(
+        merge(&[&base, &extra, &replace]),
+        "[[battery.display]]\nthreshold = 50\n"
+    );
+}
+
+#[test]
+fn scoped_layer_merges_only_selected_sections() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        "format = \"$all\"\n\n[character]\nsuccess_symbol = \">\"\n",
+    );
+    let big = write_toml(
+        &dir,
+        "big.toml",
+        r#"
+format = "$directory$character"
+add_newline = false
+
+[character]
+success_symbol = "→"
+
+[git_status]
+ahead = "⇡"
+
+[directory]
+truncation_length = 2
+"#,
+    );
+
+    let output = cmd()
+        .args([base, format!("{big}#git_status#directory")])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
+#[test]
+fn double_dash_treats_subcommand_names_as_configs() {
+    let dir = TempDir::new().unwrap();
+    write_toml(&dir, "lint", "format = \"$all\"\n");
+    write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    let output = cmd()
+        .current_dir(dir.path())
+        .args(["--", "lint", "override.toml"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n"
+    );
+
+    // A subcommand before `--` still runs, with the file named like a subcommand after it
+    let output = cmd()
+        .current_dir(dir.path())
+        .args(["fingerprint", "--", "lint", "override.toml"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(String::from_utf8(output).unwrap().trim().len(), 64);
+}
+
+#[test]
+fn fingerprint_depends_only_on_merged_content() {
+    let dir = TempDir::new().unwrap();
+
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        r#"
+format = "$all"
+
+[character]
+success_symbol = "[>](bold green)"
+"#,
+    );
+    let override_ = write_toml(
+        &dir,
+        "override.toml",
+        r#"
+[character]
+success_symbol = "[→](bold cyan)"
+"#,
+    );
+    let flat = write_toml(
+        &dir,
+        "flat.toml",
+        r#"
+character.success_symbol = "[→](bold cyan)"
+format = "$all"
+"#,
+    );
+
+    let layered = cmd()
+        .args(["fingerprint", &base, &override_])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let single = cmd()
+        .args(["fingerprint", &flat])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    assert_eq!(layered, single);
+    assert_eq!(String::from_utf8(layered).unwrap().trim().len(), 64);
+}
+
+#[test]
+fn type_check_warns_on_table_replaced_by_scalar() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(&dir, "base.toml", "[character]\nsuccess_symbol = \">\"\n");
+    let override_ = write_toml(&dir, "override.toml", "character = 1979-05-27\n");
+
+    cmd()
+        .env("STARSHIP_MERGE_TYPE_CHECK", "1")
+        .args([&base, &override_])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "override.toml: `character` replaces table with datetime",
+        ));
+}
+
+#[test]
+fn config_home_resolves_relative_paths() {
+    let home = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    write_toml(&home, "base.toml", "format = \"$all\"\n");
+    fs::create_dir(home.path().join("conf.d")).unwrap();
+    write_toml(&home, "conf.d/extra.toml", "add_newline = false\n");
+
+    let output = cmd()
+        .current_dir(cwd.path())
+        .env("STARSHIP_CONFIG_HOME", home.path())
+        .args(["base.toml", "conf.d/extra.toml"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(cached_toml, "add_newline = false\nformat = \"$all\"\n");
+
+    // A `#` in a file name found under the config home isn't a scope
+    write_toml(&home, "we#ird.toml", "add_newline = true\n");
+    let output = cmd()
+        .current_dir(cwd.path())
+        .env("STARSHIP_CONFIG_HOME", home.path())
+        .args(["base.toml", "we#ird.toml"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(cached_toml, "add_newline = true\nformat = \"$all\"\n");
+}
+
+#[test]
+fn merge_drop_in_directory() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        r#"
+format = "$all"
+
+[character]
+success_symbol = "[>](bold green)"
+"#,
+    );
+    fs::create_dir(dir.path().join("base.toml.d")).unwrap();
+    write_toml(
+        &dir,
+        "base.toml.d/10-extra.toml",
+        r#"
+[character]
+success_symbol = "[→](bold cyan)"
+"#,
+    );
+    write_toml(&dir, "base.toml.d/20-more.toml", "add_newline = false\n");
+    write_toml(&dir, "base.toml.d/README.md", "not a config\n");
+
+    let output = cmd()
+        .arg(&base)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
+#[test]
+fn header_lists_sources() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    let output = cmd()
+        .env_remove("STARSHIP_MULTI_NO_HEADER")
+        .args([&f1, &f2])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+
+    assert!(cached_toml.starts_with("# Generated by starship-multi-config at "));
+    let f1 = fs::canonicalize(f1).unwrap();
+    let f2 = fs::canonicalize(f2).unwrap();
+    assert!(cached_toml.contains(&format!(
+        " from:\n#   {}\n#   {}\n\n",
+        f1.display(),
+        f2.display()
+    )));
+    assert!(cached_toml.ends_with("\nadd_newline = false\nformat = \"$all\"\n"));
+}
+
+#[test]
+fn manifest_lists_presets_and_layers() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("test-preset", "format = \"$all\"\n")]);
+    fs::create_dir_all(dir.path().join("conf/base")).unwrap();
+    fs::create_dir_all(dir.path().join("conf/theme")).unwrap();
+    write_toml(&dir, "conf/base/10-a.toml", "add_newline = false\n");
+    write_toml(&dir, "conf/base/20-b.toml", "command_timeout = 100\n");
+    write_toml(&dir, "conf/theme/dark.toml", "palette = \"dark\"\n");
+    let extra = write_toml(&dir, "extra.toml", "scan_timeout = 10\n");
+    let manifest = write_toml(
+        &dir,
+        "conf/layers.toml",
+        "# Base first, then the theme\n\
+         presets = [\"test-preset\"]\n\
+         layers = [\"base/*.toml\", \"theme/*.toml\"]\n",
+    );
+
+    let output = cmd()
+        .env_remove("STARSHIP_MULTI_NO_HEADER")
+        .env("PATH", &stub)
+        .env("STARSHIP_CONFIG_FILE", &manifest)
+        .arg(&extra)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    let sources: Vec<&str> = cached_toml
+        .lines()
+        .filter_map(|line| line.strip_prefix("#   "))
+        .map(|path| path.rsplit('/').next().unwrap())
+        .collect();
+    assert!(sources[0].starts_with("preset-"), "{sources:?}");
+    assert_eq!(
+        sources[1..],
+        ["10-a.toml", "20-b.toml", "dark.toml", "extra.toml"]
+    );
+}
+
+#[test]
+fn manifest_profiles_select_layers() {
+    let dir = TempDir::new().unwrap();
+    write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    write_toml(&dir, "work.toml", "[kubernetes]\ndisabled = false\n");
+    write_toml(&dir, "home.toml", "[battery]\ndisabled = true\n");
+    let manifest = write_toml(
+        &dir,
+        "manifest.toml",
+        r#"
+layers = ["base.toml"]
+
+[profiles.work]
+layers = ["work.toml"]
+
+[profiles.home]
+layers = ["home.toml"]
+"#,
+    );
+
+    let merge = |profile: &str| {
+        let output = cmd()
+            .env("STARSHIP_CONFIG_FILE", &manifest)
+            .env("STARSHIP_PROFILE", profile)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let stdout = String::from_utf8(output).unwrap();
+        fs::read_to_string(stdout.trim()).unwrap()
+    };
+
+    assert_eq!(
+        merge("work"),
+        "format = \"$all\"\n\n[kubernetes]\ndisabled = false\n"
+    );
+    assert_eq!(
+        merge("home"),
+        "format = \"$all\"\n\n[battery]\ndisabled = true\n"
+    );
+
+    cmd()
+        .env("STARSHIP_CONFIG_FILE", &manifest)
+        .env("STARSHIP_PROFILE", "play")
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains(
+            "manifest.toml: no profile `play` (available: home, work)",
+        ));
+}
+
+#[test]
+fn force_config_overrides_all_sources() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("test-preset", "format = \"$all\"\n")]);
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+    let forced = write_toml(&dir, "forced.toml", "add_newline = true\n");
+
+    let expected = fs::canonicalize(&forced).unwrap();
+    cmd()
+        .env("PATH", &stub)
+        .env("STARSHIP_MULTI_FORCE_CONFIG", &forced)
+        .env("STARSHIP_MERGE_SEED", "default")
+        .args(["--preset", "test-preset", &f1, &f2])
+        .assert()
+        .success()
+        .stdout(format!("{}\n", expected.display()));
+
+    // Neither the preset nor the default seed was generated
+    assert!(!dir.path().join("starship.log").exists());
+}
+
+#[test]
+fn env_conditional_layer() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let iterm = write_toml(&dir, "iterm.toml", "add_newline = false\n");
+    let conditional = format!("env:TERM_PROGRAM=iTerm.app?{iterm}");
+
+    let merged = |term_program: &str| {
+        let output = cmd()
+            .env("TERM_PROGRAM", term_program)
+            .args([&base, &conditional])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let stdout = String::from_utf8(output).unwrap();
+        fs::read_to_string(stdout.trim()).unwrap()
+    };
+
+    assert_eq!(
+        merged("iTerm.app"),
+        "add_newline = false\nformat = \"$all\"\n"
+    );
+    // Excluded: only the base remains, so it's passed through as-is
+    assert_eq!(merged("Apple_Terminal"), "format = \"$all\"\n");
+}
+
+#[test]
+fn explain_annotates_sources() {
+    let dir = TempDir::new().unwrap();
+
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        r#"
+format = "$all"
+
+[character]
+success_symbol = "[>](bold green)"
+
+[git_branch]
+style = "bold purple"
+"#,
+    );
+    let override_ = write_toml(
+        &dir,
+        "override.toml",
+        r#"
+format = "$git_branch$character"
+
+[character]
+success_symbol = "[→](bold cyan)"
+"#,
+    );
+
+    let base = fs::canonicalize(base).unwrap();
+    let base = base.display();
+    let override_ = fs::canonicalize(override_).unwrap();
+    let override_ = override_.display();
+
+    let output = cmd()
+        .args([
+            "explain".to_string(),
+            base.to_string(),
+            override_.to_string(),
+        ])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    assert!(stdout.contains(&format!("# from {override_}\nformat = ")));
+    assert!(stdout.contains(&format!("# from {base}, {override_}\n[character]")));
+    assert!(stdout.contains(&format!("# from {base}\n[git_branch]")));
+}
+
+#[test]
+fn explain_keeps_source_comments() {
+    let dir = TempDir::new().unwrap();
+
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        r#"
+# Show everything
+format = "$all"
+
+# Branch colors
+[git_branch]
+style = "bold purple" # matches the terminal theme
+"#,
+    );
+    let override_ = write_toml(
+        &dir,
+        "override.toml",
+        r#"
+[git_branch]
+# Shorter branch names
+truncation_length = 12
+"#,
+    );
+
+    let base = fs::canonicalize(base).unwrap();
+    let base = base.display();
+
+    let output = cmd()
+        .arg("explain")
+        .arg(base.to_string())
+        .arg(override_)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    assert!(stdout.contains(&format!("# from {base}\n# Show everything\nformat = ")));
+    assert!(stdout.contains("# Branch colors\n[git_branch]"));
+    assert!(stdout.contains("style = \"bold purple\" # matches the terminal theme\n"));
+    assert!(stdout.contains("# Shorter branch names\ntruncation_length = 12"));
+}
+
+#[test]
+fn incremental_rereads_only_changed_files() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "theme.toml", "[character]\nsuccess_symbol = \">\"\n");
+    let f3 = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    let merge = || {
+        let output = cmd()
+            .env("STARSHIP_MULTI_INCREMENTAL", "1")
+            .env("STARSHIP_MULTI_LOG", "trace")
+            .args([&f1, &f2, &f3])
+            .assert()
+            .success()
+            .get_output()
+            .clone();
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    };
+    merge();
+
+    fs::write(&f2, "[character]\nsuccess_symbol = \"→\"\n").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&f2)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+
+    let (stdout, stderr) = merge();
+    let parsed: Vec<_> = stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("starship-multi-config: trace: parsing "))
+        .collect();
+    assert_eq!(parsed, [fs::canonicalize(&f2).unwrap().to_str().unwrap()]);
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n\n[character]\nsuccess_symbol = \"→\"\n"
+    );
+}
+
+#[test]
+fn fixed_output_path() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let override_ = write_toml(&dir, "override.toml", "add_newline = false\n");
+    let out = dir.path().join("out/starship.toml");
+
+    let merge = || {
+        let output = cmd()
+            .env("STARSHIP_MULTI_CACHE_DIR", dir.path().join("cache"))
+            .env("STARSHIP_MULTI_OUTPUT", &out)
+            .env("STARSHIP_MULTI_NO_HEADER", "1")
+            .args([&base, &override_])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        assert_eq!(
+            String::from_utf8(output).unwrap().trim(),
+            out.to_str().unwrap()
+        );
+        fs::read_to_string(&out).unwrap()
+    };
+    assert_eq!(merge(), "add_newline = false\nformat = \"$all\"\n");
+
+    // Unchanged sources reuse the file as-is
+    fs::write(&out, "# untouched\n").unwrap();
+    assert_eq!(merge(), "# untouched\n");
+
+    fs::write(&override_, "add_newline = true\n").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&override_)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+    assert_eq!(merge(), "add_newline = true\nformat = \"$all\"\n");
+}
+
+#[test]
+fn readonly_never_writes_cache_dir() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("test-preset", "format = \"$all\"\n")]);
+    let cache_dir = dir.path().join("cache");
+    let tmp_dir = dir.path().join("tmp");
+    fs::create_dir(&tmp_dir).unwrap();
+    let config = write_toml(&dir, "config.toml", "add_newline = false\n");
+
+    let output = cmd()
+        .env("PATH", &stub)
+        .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+        .env("STARSHIP_MULTI_READONLY", "1")
+        .env("TMPDIR", &tmp_dir)
+        .args(["--preset", "test-preset", &config, "-"])
+        .write_stdin("[character]\nsuccess_symbol = \">\"\n")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    assert!(!cache_dir.exists());
+    let stdout = String::from_utf8(output).unwrap();
+    let merged = Path::new(stdout.trim());
+    assert!(merged.starts_with(fs::canonicalize(&tmp_dir).unwrap()));
+    assert_eq!(
+        fs::read_to_string(merged).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n\n[character]\nsuccess_symbol = \">\"\n"
+    );
+}
+
+#[test]
+fn freeze_to_stdout() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let override_ = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    cmd()
+        .current_dir(dir.path())
+        .args(["freeze", "-", &base, &override_])
+        .assert()
+        .success()
+        .stdout("add_newline = false\nformat = \"$all\"\n")
+        .stderr("");
+    assert!(!dir.path().join("-").exists());
+}
+
+#[test]
+fn freeze_writes_standalone_config() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        "format = \"$all\"\n\n[character]\nsuccess_symbol = \">\"\n",
+    );
+    let override_ = write_toml(
+        &dir,
+        "override.toml",
+        "[character]\nsuccess_symbol = \"→\"\n",
+    );
+    let frozen = dir.path().join("starship.toml");
+
+    cmd()
+        .env_remove("STARSHIP_MULTI_NO_HEADER")
+        .arg("freeze")
+        .arg(&frozen)
+        .args([&base, &override_])
+        .assert()
+        .success();
+
+    let content = fs::read_to_string(&frozen).unwrap();
+    assert!(content.starts_with("# Generated by starship-multi-config at "));
+    let (_, body) = content.split_once("\n\n").unwrap();
+    assert_eq!(
+        body,
+        "format = \"$all\"\n\n[character]\nsuccess_symbol = \"→\"\n"
+    );
+
+    // An existing file is only replaced with --force
+    cmd()
+        .arg("freeze")
+        .arg(&frozen)
+        .arg(&base)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "already exists (use --force to overwrite)",
+        ));
+    cmd()
+        .args(["freeze", "--force"])
+        .arg(&frozen)
+        .arg(&base)
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(&frozen).unwrap(),
+        "format = \"$all\"\n\n[character]\nsuccess_symbol = \">\"\n"
+    );
+}
+
+#[test]
+fn apply_flattens_layers_into_base() {
+    let dir = TempDir::new().unwrap();
+    let original = "format = \"$all\"\n\n[character]\nsuccess_symbol = \">\"\n";
+    let base = write_toml(&dir, "starship.toml", original);
+    let f1 = write_toml(&dir, "10-char.toml", "[character]\nerror_symbol = \"x\"\n");
+    let f2 = write_toml(&dir, "20-newline.toml", "add_newline = false\n");
+
+    cmd()
+        .args(["apply", &base, &f1, &f2])
+        .assert()
+        .success()
+        .stdout("");
+
+    assert_eq!(
+        fs::read_to_string(&base).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n\n[character]\nerror_symbol = \"x\"\nsuccess_symbol = \">\"\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("starship.toml.bak")).unwrap(),
+        original
+    );
+
+    // --no-backup leaves the earlier backup alone
+    cmd()
+        .args(["apply", "--no-backup", &base, &f2])
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(dir.path().join("starship.toml.bak")).unwrap(),
+        original
+    );
+
+    for name in ["starship.json", "starship.toml.gz", "starship.ini"] {
+        let other = write_toml(&dir, name, "");
+        cmd()
+            .args(["apply", &other, &f2])
+            .assert()
+            .code(1)
+            .stderr(format!(
+                "starship-multi-config: {other}: can only apply to a plain TOML file\n"
+            ));
+        assert_eq!(fs::read_to_string(&other).unwrap(), "");
+    }
+}
+
+#[test]
+fn sync_writes_default_config_with_backup() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "local.toml", "add_newline = false\n");
+    let config_dir = dir.path().join("home/.config");
+    fs::create_dir_all(&config_dir).unwrap();
+    let original = "[character]\nsuccess_symbol = \">\"\n";
+    fs::write(config_dir.join("starship.toml"), original).unwrap();
+
+    let sync = |force: bool| {
+        let mut cmd = cmd();
+        cmd.env("HOME", dir.path().join("home"))
+            .env_remove("STARSHIP_CONFIG")
+            .arg("sync");
+        if force {
+            cmd.arg("--force");
+        }
+        cmd.args([&f1, &f2]).assert()
+    };
+
+    sync(false)
+        .failure()
+        .stderr(predicates::str::contains("already exists"));
+    assert_eq!(
+        fs::read_to_string(config_dir.join("starship.toml")).unwrap(),
+        original
+    );
+
+    sync(true).success().stdout("");
+    assert_eq!(
+        fs::read_to_string(config_dir.join("starship.toml")).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n"
+    );
+    assert_eq!(
+        fs::read_to_string(config_dir.join("starship.toml.bak")).unwrap(),
+        original
+    );
+}
+
+#[test]
+fn sync_refuses_to_overwrite_a_source_or_cache_file() {
+    let dir = TempDir::new().unwrap();
+    let config_dir = dir.path().join("home/.config");
+    fs::create_dir_all(config_dir.join("starship.d")).unwrap();
+    let original = "format = \"$all\"\n";
+    fs::write(config_dir.join("starship.toml"), original).unwrap();
+    fs::write(
+        config_dir.join("starship.d/10-extra.toml"),
+        "__arrays__ = \"append\"\nadd_newline = false\n",
+    )
+    .unwrap();
+
+    // Without arguments, the default config is both a source and the target
+    cmd()
+        .env("HOME", dir.path().join("home"))
+        .env_remove("STARSHIP_CONFIG")
+        .args(["sync", "--force"])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("is one of the sources"));
+    assert_eq!(
+        fs::read_to_string(config_dir.join("starship.toml")).unwrap(),
+        original
+    );
+
+    let cache_dir = dir.path().join("cache");
+    let f1 = write_toml(&dir, "base.toml", original);
+    let f2 = write_toml(&dir, "local.toml", "add_newline = false\n");
+    let output = cmd()
+        .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+        .args([&f1, &f2])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let merged = String::from_utf8(output).unwrap();
+    let merged = merged.trim();
+    cmd()
+        .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+        .env("STARSHIP_CONFIG", merged)
+        .args(["sync", "--force", &f1])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("in the cache directory"));
+    assert_eq!(
+        fs::read_to_string(merged).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n"
+    );
+}
+
+#[test]
+fn schema_describes_merged_types() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(
+        &dir,
+        "base.toml",
+        "format = \"$all\"\ncommand_timeout = 500\n\n[character]\nsuccess_symbol = \">\"\n",
+    );
+    let f2 = write_toml(
+        &dir,
+        "override.toml",
+        "[directory]\ntruncation_length = 3\ntruncate_to_repo = false\n\n[palettes.base]\ncolors = [\"red\"]\n",
+    );
+
+    let output = cmd()
+        .args(["schema", &f1, &f2])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    insta::assert_snapshot!(String::from_utf8(output).unwrap());
+}
+
+#[test]
+fn lint_keep_going_reports_every_parse_error() {
+    let dir = TempDir::new().unwrap();
+    let first = write_toml(&dir, "first.toml", "format = \n");
+    let good = write_toml(&dir, "good.toml", "add_newline = false\n");
+    let second = write_toml(&dir, "second.toml", "[git_branch\n");
+
+    // Without it, only the first error is reported
+    let output = cmd()
+        .args(["lint", &first, &good, &second])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    let stderr = String::from_utf8(output).unwrap();
+    assert!(stderr.contains("first.toml"));
+    assert!(!stderr.contains("second.toml"));
+
+    let output = cmd()
+        .args(["lint", "--keep-going", &first, &good, &second])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    let stderr = String::from_utf8(output).unwrap();
+    for path in [&first, &second] {
+        let path = fs::canonicalize(path).unwrap();
+        assert!(stderr.contains(&format!("starship-multi-config: {}: ", path.display())));
+    }
+    assert!(stderr.ends_with("starship-multi-config: 2 files failed to parse\n"));
+}
+
+#[test]
+fn lint_reports_unknown_sections() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let typo = write_toml(
+        &dir,
+        "typo.toml",
+        "[git_branche]\nstyle = \"bold purple\"\n\n[character]\nsuccess_symbol = \">\"\n",
+    );
+
+    cmd()
+        .args(["lint", &base, &typo])
+        .assert()
+        .success()
+        .stdout("unknown top-level key `git_branche` (did you mean `git_branch`?)\n");
+
+    cmd()
+        .args(["lint", "--strict", &base, &typo])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("1 unknown key found"));
+
+    cmd()
+        .args(["lint", "--strict", &base])
+        .assert()
+        .success()
+        .stdout("No unknown keys found.\n");
+}
+
+#[test]
+fn cache_env_invalidates_on_change() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    let merge = |term: &str| {
+        let output = cmd()
+            .env("STARSHIP_MULTI_CACHE_ENV", "TERM_PROGRAM,HOSTNAME")
+            .env("TERM_PROGRAM", term)
+            .env("STARSHIP_MULTI_LOG", "debug")
+            .args([&f1, &f2])
+            .assert()
+            .success()
+            .get_output()
+            .clone();
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    };
+
+    let (iterm, _) = merge("iTerm.app");
+    let (ghostty, stderr) = merge("ghostty");
+    assert_ne!(iterm, ghostty);
+    assert!(stderr.contains("cache miss"));
+
+    let (again, stderr) = merge("iTerm.app");
+    assert_eq!(iterm, again);
+    assert!(stderr.contains("cache hit"));
+}
+
+#[test]
+fn edit_opens_last_config() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("test-preset", "format = \"$all\"\n")]);
+    let base = write_toml(&dir, "base.toml", "[character]\nsuccess_symbol = \">\"\n");
+    let primary = write_toml(&dir, "primary.toml", "[git_branch]\nstyle = \"bold\"\n");
+    // Drop-ins come after their config but aren't what `edit` opens
+    fs::create_dir(dir.path().join("primary.toml.d")).unwrap();
+    let drop_in = write_toml(&dir, "primary.toml.d/10.toml", "add_newline = false\n");
+
+    let editor = dir.path().join("editor");
+    fs::write(
+        &editor,
+        "#!/bin/sh\nprintf '[package]\\ndisabled = true\\n' >> \"$1\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&editor, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = cmd()
+        .env("PATH", &stub)
+        .env_remove("VISUAL")
+        .env("EDITOR", &editor)
+        .args(["edit", "--preset", "test-preset", &base, &primary])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    assert_eq!(
+        fs::read_to_string(&primary).unwrap(),
+        "[git_branch]\nstyle = \"bold\"\n[package]\ndisabled = true\n"
+    );
+    assert_eq!(
+        fs::read_to_string(&base).unwrap(),
+        "[character]\nsuccess_symbol = \">\"\n"
+    );
+    assert_eq!(
+        fs::read_to_string(&drop_in).unwrap(),
+        "add_newline = false\n"
+    );
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert!(cached_toml.contains("[package]\ndisabled = true\n"));
+}
+
+#[test]
+fn watch_remerges_and_runs_command_on_change() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let over = write_toml(&dir, "over.toml", "add_newline = true\n");
+    let log = dir.path().join("watch.log");
+
+    let mut child =
+        std::process::Command::new(assert_cmd::cargo::cargo_bin!("starship-multi-config"))
+            .env("STARSHIP_MULTI_NO_HEADER", "1")
+            .env("STARSHIP_MULTI_CACHE_DIR", dir.path().join("cache"))
+            .env(
+                "STARSHIP_MULTI_WATCH_CMD",
+                format!("echo \"$STARSHIP_CONFIG\" >> '{}'", log.display()),
+            )
+            .args(["watch", "--interval", "20ms", &base, &over])
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+
+    let wait_for_runs = |count: usize| {
+        for _ in 0..250 {
+            let runs: Vec<String> = fs::read_to_string(&log)
+                .unwrap_or_default()
+                .lines()
+                .map(String::from)
+                .collect();
+            if runs.len() >= count {
+                return runs;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("watch command didn't run {count} times");
+    };
+
+    let first = wait_for_runs(1);
+    fs::write(&over, "add_newline = false\n").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&over)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+    let runs = wait_for_runs(2);
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert_ne!(runs[1], first[0]);
+    assert_eq!(
+        fs::read_to_string(&runs[1]).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n"
+    );
+}
+
+#[test]
+fn oversized_output_warns_then_fails() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let big = write_toml(
+        &dir,
+        "big.toml",
+        &format!("[custom.big]\ncommand = \"{}\"\n", "x".repeat(2000)),
+    );
+
+    cmd()
+        .env("STARSHIP_MULTI_MAX_OUTPUT", "1000")
+        .args([&f1, &big])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "bytes, over 1000 (STARSHIP_MULTI_MAX_OUTPUT)",
+        ));
+
+    cmd()
+        .env("STARSHIP_MULTI_MAX_OUTPUT", "1000")
+        .env("STARSHIP_MULTI_MAX_OUTPUT_HARD", "1500")
+        .args([&f1, &big, "-"])
+        .write_stdin("add_newline = false\n")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("bytes, over the limit of 1500"));
+}
+
+#[test]
+fn cache_files_are_private_by_default() {
+    let dir = TempDir::new().unwrap();
+    let cache_dir = dir.path().join("cache");
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    let mode = |umask: Option<&str>| {
+        let mut cmd = cmd();
+        if let Some(umask) = umask {
+            cmd.env("STARSHIP_MULTI_UMASK", umask);
+        }
+        let output = cmd
+            .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+            .arg(&f1)
+            .arg(&f2)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let cache_file = String::from_utf8(output).unwrap();
+        fs::metadata(cache_file.trim())
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777
+    };
+
+    assert_eq!(mode(None), 0o600);
+    // Changing the umask doesn't change the cache key, so remove the old file
+    fs::remove_dir_all(&cache_dir).unwrap();
+    assert_eq!(mode(Some("022")), 0o644);
+}
+
+#[test]
+fn trace_appends_line_per_run() {
+    let dir = TempDir::new().unwrap();
+    let cache_dir = dir.path().join("cache");
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    let run = || {
+        let output = cmd()
+            .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+            .env("STARSHIP_MULTI_TRACE", "1")
+            .args([&f1, &f2])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(output).unwrap().trim().to_string()
+    };
+
+    let merged = run();
+    run();
+
+    let trace = fs::read_to_string(cache_dir.join("trace.log")).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert_eq!(lines.len(), 2, "{trace}");
+    for (line, cache) in lines.iter().zip(["miss", "hit"]) {
+        let fields: Vec<&str> = line.split(' ').collect();
+        assert!(humantime::parse_rfc3339(fields[0]).is_ok(), "{line}");
+        assert_eq!(fields[1], "sources=2");
+        assert_eq!(fields[2], format!("cache={cache}"));
+        assert!(fields[3].starts_with("elapsed="), "{line}");
+        assert_eq!(fields[4], format!("path={merged}"));
+    }
+}
+
+#[test]
+fn debug_log_reports_cache_miss() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    cmd()
+        .env("STARSHIP_MULTI_LOG", "debug")
+        .args([&f1, &f2])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "starship-multi-config: debug: cache miss: ",
+        ));
+}
+
+#[test]
+fn unwritable_cache_falls_back_to_temp_file() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+    // A cache dir nested under a regular file can never be created
+    let blocker = write_toml(&dir, "blocker", "");
+
+    let output = cmd()
+        .env(
+            "STARSHIP_MULTI_CACHE_DIR",
+            Path::new(&blocker).join("cache"),
+        )
+        .args([&f1, &f2])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("using a temporary file instead"))
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let merged_path = stdout.trim();
+    assert!(!merged_path.starts_with(&blocker));
+
+    let merged = fs::read_to_string(merged_path).unwrap();
+    fs::remove_file(merged_path).unwrap();
+    assert_eq!(merged, "add_newline = false\nformat = \"$all\"\n");
+}
+
+#[test]
+fn merges_without_home_directory() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("test-preset", "format = \"$all\"\n")]);
+    let config = write_toml(&dir, "config.toml", "add_newline = false\n");
+
+    // Without HOME, the cache dir comes from the passwd entry if there is one, and
+    // otherwise can't be determined, so merging has to work either way
+    let output = cmd()
+        .env_remove("HOME")
+        .env_remove("XDG_CACHE_HOME")
+        .env_remove("STARSHIP_MULTI_CACHE_DIR")
+        .env("PATH", &stub)
+        .args(["--preset", "test-preset", &config])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n"
+    );
+}
+
+#[test]
+fn tilde_expands_in_cache_and_config_dirs() {
+    let home = TempDir::new().unwrap();
+    fs::create_dir(home.path().join("team")).unwrap();
+    write_toml(&home, "team/base.toml", "format = \"$all\"\n");
+    let config = write_toml(&home, "config.toml", "add_newline = false\n");
+
+    let output = cmd()
+        .env("HOME", home.path())
+        .env("STARSHIP_MULTI_CACHE_DIR", "~/cache")
+        .env("STARSHIP_CONFIG_DIR", "~/team")
+        .arg(&config)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let merged = Path::new(stdout.trim());
+    assert!(merged.starts_with(home.path().join("cache")));
+    assert_eq!(
+        fs::read_to_string(merged).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n"
+    );
+}
+
+#[test]
+fn default_config_with_drop_ins() {
+    let home = TempDir::new().unwrap();
+    fs::create_dir_all(home.path().join(".config/starship.d")).unwrap();
+    write_toml(
+        &home,
+        ".config/starship.toml",
+        r#"
+format = "$all"
+
+[character]
+success_symbol = "[>](bold green)"
+"#,
+    );
+    write_toml(
+        &home,
+        ".config/starship.d/extra.toml",
+        r#"
+[character]
+success_symbol = "[→](bold cyan)"
+"#,
+    );
+
+    let output = cmd()
+        .env("HOME", home.path())
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
+#[test]
+fn unmatched_globs_fall_back_to_default_config() {
+    let home = TempDir::new().unwrap();
+    fs::create_dir(home.path().join(".config")).unwrap();
+    let default_config = write_toml(&home, ".config/starship.toml", "format = \"$all\"\n");
+
+    let expected = fs::canonicalize(&default_config).unwrap();
+    cmd()
+        .env("HOME", home.path())
+        .current_dir(home.path())
+        .args(["conf.d/*.toml", "local/*.toml"])
+        .assert()
+        .success()
+        .stdout(format!("{}\n", expected.display()));
+
+    fs::remove_file(&default_config).unwrap();
+    cmd()
+        .env("HOME", home.path())
+        .current_dir(home.path())
+        .args(["conf.d/*.toml"])
+        .assert()
+        .code(1)
+        .stderr("starship-multi-config: no config files matched, and no default config found\n");
+}
+
+#[test]
+fn require_first_rejects_missing_or_empty_base() {
+    let dir = TempDir::new().unwrap();
+    let local = write_toml(&dir, "local.toml", "add_newline = false\n");
+    let empty = write_toml(&dir, "empty.toml", "# nothing here yet\n");
+    let glob = dir.path().join("policy/*.toml");
+    let glob = glob.to_str().unwrap();
+
+    // Without the option, the unmatched glob is skipped
+    cmd().args([glob, &local]).assert().success();
+
+    cmd()
+        .env("STARSHIP_MULTI_REQUIRE_FIRST", "1")
+        .args([glob, &local])
+        .assert()
+        .code(1)
+        .stderr(format!(
+            "starship-multi-config: {glob}: required first config matched no files\n"
+        ));
+
+    cmd()
+        .env("STARSHIP_MULTI_REQUIRE_FIRST", "1")
+        .args([&empty, &local])
+        .assert()
+        .code(1)
+        .stderr(format!(
+            "starship-multi-config: {}: required first config is empty\n",
+            fs::canonicalize(&empty).unwrap().display()
+        ));
+
+    cmd()
+        .env("STARSHIP_MULTI_REQUIRE_FIRST", "1")
+        .args([&local, &empty])
+        .assert()
+        .success();
+
+    // A preset before it isn't the first config
+    cmd()
+        .env("STARSHIP_MULTI_REQUIRE_FIRST", "1")
+        .args(["--preset", "builtin:minimal", &empty, &local])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("required first config is empty"));
+
+    let conditional = format!("env:STARSHIP_MULTI_TEST_UNSET=1?{local}");
+    cmd()
+        .env("STARSHIP_MULTI_REQUIRE_FIRST", "1")
+        .env_remove("STARSHIP_MULTI_TEST_UNSET")
+        .args([&conditional, &empty])
+        .assert()
+        .code(1)
+        .stderr(format!(
+            "starship-multi-config: {conditional}: required first config is skipped by its env condition\n"
+        ));
+}
+
+#[test]
+fn env_lists_values_and_defaults() {
+    let output = cmd()
+        .env("STARSHIP_PRESET", "nerd-font-symbols")
+        .env("STARSHIP_MULTI_UMASK", "022")
+        .env_remove("STARSHIP_MULTI_TOML_STYLE")
+        .env_remove("STARSHIP_PROFILE")
+        .arg("env")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    let lines: Vec<Vec<&str>> = stdout
+        .lines()
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert!(lines.contains(&vec!["STARSHIP_PRESET", "nerd-font-symbols"]));
+    assert!(lines.contains(&vec!["STARSHIP_MULTI_UMASK", "022"]));
+    assert!(lines.contains(&vec!["STARSHIP_MULTI_TOML_STYLE", "(default:", "compact)"]));
+    assert!(lines.contains(&vec!["STARSHIP_PROFILE", "(unset)"]));
+}
+
+#[test]
+fn starship_resolving_to_self_is_an_error() {
+    let dir = TempDir::new().unwrap();
+    let bin_dir = dir.path().join("bin");
+    fs::create_dir(&bin_dir).unwrap();
+    let starship = bin_dir.join("starship");
+    symlink(env!("CARGO_BIN_EXE_starship-multi-config"), &starship).unwrap();
+    let config = write_toml(&dir, "config.toml", "add_newline = false\n");
+
+    cmd()
+        .env("PATH", &bin_dir)
+        .env_remove("STARSHIP")
+        .args(["--preset", "nerd-font-symbols", &config])
+        .assert()
+        .code(1)
+        .stderr(format!(
+            "starship-multi-config: {}: is starship-multi-config itself, not starship; set \
+             STARSHIP to the real starship binary\n",
+            starship.display()
+        ));
+}
+
+#[test]
+fn doctor_flags_unwritable_cache_dir() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[]);
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+    let blocker = write_toml(&dir, "blocker", "");
+
+    cmd()
+        .env("PATH", &stub)
+        .env(
+            "STARSHIP_MULTI_CACHE_DIR",
+            Path::new(&blocker).join("cache"),
+        )
+        .args(["doctor", &f1, &f2])
+        .assert()
+        .code(1)
+        .stdout(predicates::str::contains("(not writable: "))
+        .stdout(predicates::str::contains(
+            "sources: 2 files, merged into a cached config",
+        ))
+        .stderr(predicates::str::contains("1 problem found"));
+}
+
+#[test]
+fn doctor_resolves_config_dir() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[]);
+    let drop_ins = dir.path().join("dd");
+    fs::create_dir(&drop_ins).unwrap();
+    fs::write(drop_ins.join("10-base.toml"), "format = \"$all\"\n").unwrap();
+    fs::write(drop_ins.join("20-local.toml"), "add_newline = false\n").unwrap();
+
+    cmd()
+        .env("PATH", &stub)
+        .env("HOME", dir.path().join("home"))
+        .env("STARSHIP_MULTI_CACHE_DIR", dir.path().join("cache"))
+        .env("STARSHIP_CONFIG_DIR", &drop_ins)
+        .arg("doctor")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "sources: 2 files, merged into a cached config",
+        ));
+}
+
+#[test]
+fn doctor_reports_single_sources_that_are_merged() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[]);
+    let toml = write_toml(&dir, "plain.toml", "add_newline = false\n");
+    let json = write_toml(&dir, "config.json", "{\"add_newline\": false}\n");
+
+    let doctor = |args: &[&str]| {
+        let output = cmd()
+            .env("PATH", &stub)
+            .env("STARSHIP_MULTI_CACHE_DIR", dir.path().join("cache"))
+            .arg("doctor")
+            .args(args)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(output).unwrap()
+    };
+
+    assert!(doctor(&[&toml]).contains("sources: 1 file, passed through unmerged"));
+    // JSON has to be converted, and `--set` applied, so neither passes through
+    assert!(doctor(&[&json]).contains("sources: 1 file, merged into a cached config"));
+    assert!(
+        doctor(&["--set", "format=\"$all\"", &toml])
+            .contains("sources: 1 file, merged into a cached config")
+    );
+}
+
+#[test]
+fn explain_and_doctor_json() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("test-preset", "palette = \"p\"\n")]);
+    let common = write_toml(&dir, "common.toml", "command_timeout = 100\n");
+    let f1 = write_toml(
+        &dir,
+        "base.toml",
+        "# multi: include=common.toml\nformat = \"$all\"\n[character]\nsymbol = \">\"\n",
+    );
+    let f2 = write_toml(
+        &dir,
+        "override.toml",
+        "__priority__ = -1\nadd_newline = false\n",
+    );
+    let [common_path, f1_path, f2_path] = [&common, &f1, &f2].map(|f| fs::canonicalize(f).unwrap());
+
+    let json = |args: &[&str]| {
+        let output = cmd()
+            .env("PATH", &stub)
+            .env("STARSHIP_MULTI_CACHE_DIR", dir.path().join("cache"))
+            .args(args)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        serde_json::from_slice::<serde_json::Value>(&output).unwrap()
+    };
+
+    // Sources are listed in merge order, after priorities
+    let explained = json(&[
+        "explain",
+        "--format",
+        "json",
+        "--preset",
+        "test-preset",
+        &f1,
+        &f2,
+    ]);
+    let preset_path = explained["sources"][1]["path"].clone();
+    assert_eq!(
+        explained,
+        serde_json::json!({
+            "sources": [
+                { "path": f2_path, "kind": "config", "order": 0 },
+                { "path": preset_path, "kind": "preset", "order": 1 },
+                { "path": common_path, "kind": "include", "order": 2 },
+                { "path": f1_path, "kind": "config", "order": 3 },
+            ],
+            "keys": {
+                "add_newline": [f2_path],
+                "character": [f1_path],
+                "command_timeout": [common_path],
+                "format": [f1_path],
+                "palette": [preset_path],
+            },
+        })
+    );
+
+    let report = json(&[
+        "doctor",
+        "--format",
+        "json",
+        "--preset",
+        "test-preset",
+        &f1,
+        &f2,
+    ]);
+    assert_eq!(report["problems"], 0);
+    assert_eq!(report["cache_dir"]["writable"], true);
+    // Doctor lists sources as resolved, before priorities apply
+    assert_eq!(
+        report["sources"],
+        serde_json::json!([
+            { "path": preset_path, "kind": "preset", "order": 0 },
+            { "path": common_path, "kind": "include", "order": 1 },
+            { "path": f1_path, "kind": "config", "order": 2 },
+            { "path": f2_path, "kind": "config", "order": 3 },
+        ])
+    );
+}
+
+#[test]
+fn report_counts_overrides_and_deletes() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(
+        &dir,
+        "base.toml",
+        "format = \"$all\"\nadd_newline = true\n\n[character]\nsuccess_symbol = \">\"\n",
+    );
+    let override_ = write_toml(
+        &dir,
+        "override.toml",
+        "add_newline = false\n\n[character]\nsuccess_symbol = \"__delete__\"\n\n\
+         [git_branch]\nsymbol = \"git \"\n",
+    );
+    let [base, override_] = [&base, &override_].map(|f| fs::canonicalize(f).unwrap());
+
+    cmd()
+        .arg("report")
+        .args([&base, &override_])
+        .assert()
+        .success()
+        .stdout(format!(
+            "keys: 3\noverridden: 1\ndeleted: 1\nsources:\n  \
+             {}: 3 added, 0 overridden\n  {}: 1 added, 1 overridden\n",
+            base.display(),
+            override_.display()
+        ));
+}
+
+#[test]
+fn explain_and_report_ignore_keys_a_fill_only_layer_kept() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(&dir, "base.toml", "format = \"base\"\n");
+    let fill = write_toml(
+        &dir,
+        "fill.toml",
+        "__fill_only__ = true\nformat = \"fill\"\nadd_newline = false\n",
+    );
+    let [base, fill] = [&base, &fill].map(|f| fs::canonicalize(f).unwrap());
+
+    let output = cmd()
+        .arg("explain")
+        .args([&base, &fill])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert!(
+        stdout.contains(&format!("# from {}\nformat = \"base\"", base.display())),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains(&format!("# from {}\nadd_newline = false", fill.display())),
+        "{stdout}"
+    );
+
+    cmd()
+        .arg("report")
+        .args([&base, &fill])
+        .assert()
+        .success()
+        .stdout(format!(
+            "keys: 2\noverridden: 0\ndeleted: 0\nsources:\n  \
+             {}: 1 added, 0 overridden\n  {}: 1 added, 0 overridden\n",
+            base.display(),
+            fill.display()
+        ));
+}
+
+#[test]
+fn bench_prints_cold_and_warm_timings() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    let output = cmd()
+        .args(["bench", &f1, &f2])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2, "unexpected output: {stdout}");
+    assert!(lines[0].starts_with("cold merge (2 sources): "));
+    assert!(lines[1].starts_with("warm cache hit: "));
+}
+
+#[test]
+fn symlinked_source_target_edit_invalidates_cache() {
+    let dir = TempDir::new().unwrap();
+    let target = write_toml(&dir, "real.toml", "format = \"$all\"\n");
+    let link = dir.path().join("link.toml");
+    symlink(&target, &link).unwrap();
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    let merge = || {
+        let output = cmd()
+            .args([link.to_str().unwrap(), &f2])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(output).unwrap().trim().to_string()
+    };
+
+    let first = merge();
+    assert_eq!(
+        fs::read_to_string(&first).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n"
+    );
+
+    fs::write(&target, "format = \"$character\"\n").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&target)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+
+    let second = merge();
+    assert_ne!(first, second);
+    assert_eq!(
+        fs::read_to_string(&second).unwrap(),
+        "add_newline = false\nformat = \"$character\"\n"
+    );
+}
+
+#[test]
+fn postprocess_transforms_merged_config() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(
+        &dir,
+        "base.toml",
+        "[character]\nsuccess_symbol = \"[>](bold green)\"\n",
+    );
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+    let script = dir.path().join("shout");
+    fs::write(&script, "#!/bin/sh\nsed 's/bold green/BOLD GREEN/'\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = cmd()
+        .env("STARSHIP_MULTI_POSTPROCESS", &script)
+        .args([&f1, &f2])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(
+        cached_toml,
+        "add_newline = false\n\n[character]\nsuccess_symbol = \"[>](BOLD GREEN)\"\n"
+    );
+
+    // A single source is postprocessed too
+    let output = cmd()
+        .env("STARSHIP_MULTI_POSTPROCESS", &script)
+        .arg(&f1)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "[character]\nsuccess_symbol = \"[>](BOLD GREEN)\"\n"
+    );
+}
+
+#[test]
+fn output_paths_expand_home_and_vars() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(
+        &dir,
+        "base.toml",
+        "[character]\nsuccess_symbol = \"bold green\"\n",
+    );
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+    let script = dir.path().join("shout");
+    fs::write(&script, "#!/bin/sh\nsed 's/bold green/BOLD GREEN/'\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let home = || {
+        let mut cmd = cmd();
+        cmd.env("HOME", dir.path()).env("OUT", dir.path());
+        cmd
+    };
+
+    home()
+        .env("STARSHIP_MULTI_POSTPROCESS", "~/shout")
+        .args(["freeze", "$OUT/frozen.toml", &f1, &f2])
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(dir.path().join("frozen.toml")).unwrap(),
+        "add_newline = false\n\n[character]\nsuccess_symbol = \"BOLD GREEN\"\n"
+    );
+
+    home()
+        .args(["apply", "--no-backup", "~/base.toml", &f2])
+        .assert()
+        .success();
+    assert!(
+        fs::read_to_string(&f1)
+            .unwrap()
+            .contains("add_newline = false")
+    );
+
+    home()
+        .args(["migrate", "~/conf.d", &f1, &f2])
+        .assert()
+        .success();
+    assert!(dir.path().join("conf.d/010-base.toml").exists());
+}
+
+#[test]
+fn postprocess_failure_is_reported() {
+    let dir = TempDir::new().unwrap();
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+    let script = dir.path().join("broken");
+    fs::write(&script, "#!/bin/sh\necho 'theme not found' >&2\nexit 3\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+    cmd()
+        .env("STARSHIP_MULTI_POSTPROCESS", &script)
+        .args([&f1, &f2])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("theme not found"));
+}
+
 #[test]
-fn single_config_passthrough() {
+fn verify_warns_when_starship_rejects_config() {
     let dir = TempDir::new().unwrap();
     let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "[git_branche]\nstyle = \"red\"\n");
+    let stub = dir.path().join("starship");
+    fs::write(
+        &stub,
+        "#!/bin/sh\necho \"[WARN] - (starship::config): Unknown key git_branche in $STARSHIP_CONFIG\" >&2\nexit 1\n",
+    )
+    .unwrap();
+    fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
 
-    cmd().arg(&f1).assert().success().stdout(format!("{f1}\n"));
+    cmd()
+        .env("PATH", dir.path())
+        .env("STARSHIP_MULTI_VERIFY", "1")
+        .args([&f1, &f2])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("starship print-config rejected"))
+        .stderr(predicates::str::contains("Unknown key git_branche"));
 }
 
 #[test]
-fn merge_two_files() {
+fn migrate_copies_sources_in_order() {
     let dir = TempDir::new().unwrap();
-
-    let f1 = write_toml(
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    write_toml(&dir, "common.toml", "[character]\nerror_symbol = \"x\"\n");
+    let f2 = write_toml(
         &dir,
-        "base.toml",
-        r#"
-format = "$all"
-
-[character]
-success_symbol = "[>](bold green)"
-error_symbol = "[>](bold red)"
-"#,
+        "theme.toml",
+        "# multi: include=common.toml, priority=0\n[character]\nsuccess_symbol = \">\"\n",
     );
+    let f3 = write_toml(&dir, "another.toml", "add_newline = false\n");
+    let target = dir.path().join("conf.d");
 
-    let f2 = write_toml(
-        &dir,
-        "override.toml",
-        r#"
-[character]
-success_symbol = "[→](bold cyan)"
+    cmd()
+        .args(["migrate", target.to_str().unwrap(), &f1, &f2, &f3])
+        .assert()
+        .success()
+        .stdout(format!(
+            "export STARSHIP_CONFIG_DIR='{}'\n",
+            fs::canonicalize(&target).unwrap().display()
+        ));
 
-[package]
-disabled = true
-"#,
+    let mut copied: Vec<_> = fs::read_dir(&target)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    copied.sort();
+    assert_eq!(
+        copied,
+        [
+            "010-base.toml",
+            "020-common.toml",
+            "030-theme.toml",
+            "040-another.toml"
+        ]
+    );
+    assert_eq!(
+        fs::read_to_string(target.join("040-another.toml")).unwrap(),
+        "add_newline = false\n"
+    );
+    // The include was copied ahead, so the copy doesn't include it again
+    assert_eq!(
+        fs::read_to_string(target.join("030-theme.toml")).unwrap(),
+        "# multi: priority=0\n[character]\nsuccess_symbol = \">\"\n"
     );
-
     let output = cmd()
-        .args([&f1, &f2])
+        .arg(target.join("*.toml"))
         .assert()
         .success()
         .get_output()
         .stdout
         .clone();
-
     let stdout = String::from_utf8(output).unwrap();
-    let cache_path = stdout.trim();
-
-    assert!(
-        Path::new(cache_path).exists(),
-        "cache file should exist at {cache_path}"
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\nformat = \"$all\"\n\n\
+         [character]\nerror_symbol = \"x\"\nsuccess_symbol = \">\"\n"
     );
 
-    let cached_toml = fs::read_to_string(cache_path).unwrap();
-    insta::assert_snapshot!(cached_toml);
+    // Existing files are only replaced with --force
+    cmd()
+        .args(["migrate", target.to_str().unwrap(), &f1])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("already exists"));
+    cmd()
+        .args(["migrate", "--force", target.to_str().unwrap(), &f1])
+        .assert()
+        .success();
+    // ...which also removes drop-ins left over from before, but not other files
+    fs::write(target.join("notes.txt"), "").unwrap();
+    cmd()
+        .args(["migrate", "--force", target.to_str().unwrap(), &f1])
+        .assert()
+        .success();
+    let mut copied: Vec<_> = fs::read_dir(&target)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    copied.sort();
+    assert_eq!(copied, ["010-base.toml", "notes.txt"]);
+}
+
+#[test]
+fn migrate_pads_prefixes_to_the_source_count() {
+    let dir = TempDir::new().unwrap();
+    fs::create_dir(dir.path().join("src")).unwrap();
+    for i in 0..100 {
+        write_toml(&dir, &format!("src/{i:03}.toml"), "");
+    }
+
+    cmd()
+        .current_dir(dir.path())
+        .args(["migrate", "out", "src/*.toml"])
+        .assert()
+        .success()
+        .stdout(format!(
+            "export STARSHIP_CONFIG_DIR='{}'\n",
+            fs::canonicalize(dir.path().join("out")).unwrap().display()
+        ));
+
+    let mut copied: Vec<_> = fs::read_dir(dir.path().join("out"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    copied.sort();
+    assert_eq!(copied.len(), 100);
+    assert_eq!(copied[0], "0010-000.toml");
+    assert_eq!(copied[99], "1000-099.toml");
 }
 
 #[test]
 fn no_args_shows_error() {
+    let home = TempDir::new().unwrap();
     cmd()
+        .env("HOME", home.path())
         .assert()
         .failure()
         .stderr(predicates::str::contains("Usage"));
@@ -122,6 +3310,157 @@ fn invalid_toml_error() {
         .stderr(predicates::str::contains("bad.toml"));
 }
 
+#[test]
+fn invalid_edit_keeps_previous_cache() {
+    let dir = TempDir::new().unwrap();
+    let cache_dir = dir.path().join("cache");
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(&dir, "override.toml", "add_newline = false\n");
+
+    let output = cmd()
+        .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+        .args([&f1, &f2])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let cache_file = String::from_utf8(output).unwrap().trim().to_string();
+    let before = fs::read_to_string(&cache_file).unwrap();
+
+    // A half-finished edit of the last layer fails the merge without writing anything
+    fs::write(&f2, "add_newline = ").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&f2)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(10))
+        .unwrap();
+    cmd()
+        .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+        .args([&f1, &f2])
+        .assert()
+        .code(1)
+        .stderr(predicates::str::contains("override.toml"));
+
+    assert_eq!(fs::read_to_string(&cache_file).unwrap(), before);
+    assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 1);
+}
+
+#[test]
+fn merge_depth_limit() {
+    let dir = TempDir::new().unwrap();
+    let base = write_toml(&dir, "base.toml", "[a.b.c.d]\nx = 1\n");
+    let override_ = write_toml(&dir, "override.toml", "[a.b.c.d]\ny = 2\n");
+
+    // Within the default limit, the deepest tables merge
+    let output = cmd()
+        .args([&base, &override_])
+        .assert()
+        .success()
+        .stderr("")
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(cached_toml, "[a.b.c.d]\nx = 1\ny = 2\n");
+
+    let output = cmd()
+        .env("STARSHIP_MERGE_MAX_DEPTH", "3")
+        .args([&base, &override_])
+        .assert()
+        .success()
+        .stderr(
+            "starship-multi-config: warning: `a.b.c.d` is nested more than 3 tables deep; \
+             replacing it instead of merging\n",
+        )
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(cached_toml, "[a.b.c.d]\ny = 2\n");
+
+    // An invalid limit is rejected up front rather than once per layer
+    cmd()
+        .env("STARSHIP_MERGE_MAX_DEPTH", "deep")
+        .args([&base, &override_])
+        .assert()
+        .code(2)
+        .stderr(predicates::str::contains(
+            "invalid value 'deep' for '--merge-max-depth <N>'",
+        ));
+}
+
+#[test]
+fn non_utf8_file_in_glob() {
+    let dir = TempDir::new().unwrap();
+    write_toml(&dir, "10-base.toml", "format = \"$all\"\n");
+    write_toml(&dir, "20-override.toml", "add_newline = false\n");
+    fs::write(dir.path().join("30-blob.toml"), [0xff, 0xfe, 0x00, 0x80]).unwrap();
+    let blob = fs::canonicalize(dir.path().join("30-blob.toml")).unwrap();
+
+    cmd()
+        .current_dir(dir.path())
+        .arg("*.toml")
+        .assert()
+        .code(1)
+        .stderr(format!(
+            "starship-multi-config: {}: not valid UTF-8\n",
+            blob.display()
+        ));
+
+    let output = cmd()
+        .current_dir(dir.path())
+        .env("STARSHIP_MULTI_SKIP_UNREADABLE", "1")
+        .arg("*.toml")
+        .assert()
+        .success()
+        .stderr(format!(
+            "starship-multi-config: warning: {}: not valid UTF-8 (skipping)\n",
+            blob.display()
+        ))
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    assert_eq!(cached_toml, "add_newline = false\nformat = \"$all\"\n");
+}
+
+#[test]
+fn builtin_preset_needs_no_starship() {
+    let dir = TempDir::new().unwrap();
+    let empty_path = dir.path().join("bin");
+    fs::create_dir(&empty_path).unwrap();
+    let config = write_toml(&dir, "config.toml", "[directory]\ntruncation_length = 5\n");
+
+    let output = cmd()
+        .env("PATH", &empty_path)
+        .env_remove("STARSHIP")
+        .env("STARSHIP_PRESET", "builtin:minimal")
+        .arg(&config)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let stdout = String::from_utf8(output).unwrap();
+    assert_eq!(
+        fs::read_to_string(stdout.trim()).unwrap(),
+        "add_newline = false\nformat = \"$directory$git_branch$character\"\n\n\
+         [directory]\ntruncation_length = 5\n\n[git_branch]\nformat = \"[$branch]($style) \"\n"
+    );
+
+    cmd()
+        .env("PATH", &empty_path)
+        .args(["--preset", "builtin:fancy", &config])
+        .assert()
+        .code(1)
+        .stderr("starship-multi-config: no built-in preset `fancy` (available: ascii, minimal)\n");
+}
+
 #[test]
 fn preset_only() {
     let dir = TempDir::new().unwrap();
@@ -155,6 +3494,139 @@ success_symbol = "[→](bold cyan)"
     insta::assert_snapshot!(cached_toml);
 }
 
+#[test]
+fn starship_bin_overrides_path() {
+    let dir = TempDir::new().unwrap();
+    write_starship_stub(&dir, &[("test-preset", "format = \"$all\"\n")]);
+
+    // The stub isn't on the PATH, so only the flag can find it
+    cmd()
+        .env("STARSHIP", "/nonexistent/starship")
+        .args(["--preset", "test-preset", "--starship-bin"])
+        .arg(dir.path().join("starship"))
+        .assert()
+        .success();
+
+    let log = fs::read_to_string(dir.path().join("starship.log")).unwrap();
+    assert_eq!(log, "preset test-preset\n");
+}
+
+#[test]
+fn default_seed_underlies_configs() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[]);
+    write_toml(
+        &dir,
+        "default.toml",
+        r#"
+add_newline = true
+
+[character]
+success_symbol = "[❯](bold green)"
+error_symbol = "[❯](bold red)"
+"#,
+    );
+    let f1 = write_toml(&dir, "base.toml", "format = \"$all\"\n");
+    let f2 = write_toml(
+        &dir,
+        "override.toml",
+        "[character]\nsuccess_symbol = \"[→](bold cyan)\"\n",
+    );
+
+    let output = cmd()
+        .env("PATH", &stub)
+        .env("STARSHIP_MERGE_SEED", "default")
+        .args([&f1, &f2])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
+#[test]
+fn preset_list_prints_available_presets() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(
+        &dir,
+        &[
+            ("gruvbox-rainbow", "format = \"$all\"\n"),
+            ("nerd-font-symbols", "format = \"$all\"\n"),
+        ],
+    );
+    let config = write_toml(&dir, "config.toml", "add_newline = false\n");
+
+    cmd()
+        .env("PATH", &stub)
+        .env("STARSHIP_PRESET", "list")
+        .arg(&config)
+        .assert()
+        .success()
+        .stdout("gruvbox-rainbow\nnerd-font-symbols\nbuiltin:ascii\nbuiltin:minimal\n");
+
+    let log = fs::read_to_string(dir.path().join("starship.log")).unwrap();
+    assert_eq!(log, "preset --list\n");
+}
+
+#[test]
+fn empty_preset_warns() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("no-such-preset", "")]);
+    let config = write_toml(&dir, "config.toml", "add_newline = false\n");
+
+    cmd()
+        .env("PATH", &stub)
+        .env("STARSHIP_MULTI_READONLY", "1")
+        .args(["--preset", "no-such-preset", &config])
+        .assert()
+        .success()
+        .stderr(
+            "starship-multi-config: warning: starship preset no-such-preset printed an empty \
+             config\n",
+        );
+}
+
+#[test]
+fn preset_from_local_file() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[]);
+    let preset = write_toml(
+        &dir,
+        "my-preset.toml",
+        r#"
+format = "$all"
+
+[character]
+success_symbol = "[>](bold green)"
+error_symbol = "[>](bold red)"
+"#,
+    );
+    let config = write_toml(
+        &dir,
+        "config.toml",
+        "[character]\nsuccess_symbol = \"[→](bold cyan)\"\n",
+    );
+
+    let output = cmd()
+        .env("PATH", &stub)
+        .args(["--preset", &preset, &config])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    // The preset file is used as-is, without running starship
+    assert!(!dir.path().join("starship.log").exists());
+    let stdout = String::from_utf8(output).unwrap();
+    let cached_toml = fs::read_to_string(stdout.trim()).unwrap();
+    insta::assert_snapshot!(cached_toml);
+}
+
 #[test]
 fn preset_with_user_config() {
     let dir = TempDir::new().unwrap();
@@ -204,6 +3676,123 @@ disabled = true
     insta::assert_snapshot!(cached_toml);
 }
 
+#[test]
+fn preset_no_cache_reruns_preset_command() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("test-preset", "format = \"$all\"\n")]);
+    let user_config = write_toml(&dir, "user.toml", "add_newline = false\n");
+
+    for _ in 0..2 {
+        cmd()
+            .env("PATH", &stub)
+            .env("STARSHIP_PRESET_NO_CACHE", "1")
+            .args(["--preset", "test-preset", &user_config])
+            .assert()
+            .success();
+    }
+
+    let log = fs::read_to_string(dir.path().join("starship.log")).unwrap();
+    assert_eq!(log, "preset test-preset\npreset test-preset\n");
+}
+
+#[test]
+fn preset_version_change_regenerates_preset() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("test-preset", "format = \"$all\"\n")]);
+    let user_config = write_toml(&dir, "user.toml", "add_newline = false\n");
+
+    let run = || {
+        cmd()
+            .env("PATH", &stub)
+            .env("STARSHIP_PRESET_VERSION", "1")
+            .args(["--preset", "test-preset", &user_config])
+            .assert()
+            .success();
+    };
+
+    run();
+    run();
+    fs::write(dir.path().join("version"), "starship 1.1.0\n").unwrap();
+    run();
+
+    let log = fs::read_to_string(dir.path().join("starship.log")).unwrap();
+    let preset_runs = log.lines().filter(|l| *l == "preset test-preset").count();
+    assert_eq!(preset_runs, 2, "unexpected starship calls:\n{log}");
+}
+
+#[test]
+fn preset_cache_key_name_ignores_binary_changes() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("test-preset", "format = \"$all\"\n")]);
+    let cache_dir = dir.path().join("cache");
+    let user_config = write_toml(&dir, "user.toml", "add_newline = false\n");
+
+    let run = |key: &str| {
+        cmd()
+            .env("PATH", &stub)
+            .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+            .env("STARSHIP_PRESET_CACHE_KEY", key)
+            .args(["--preset", "test-preset", &user_config])
+            .assert()
+            .success();
+    };
+    let touch_binary = |secs| {
+        fs::File::options()
+            .write(true)
+            .open(dir.path().join("starship"))
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(secs))
+            .unwrap();
+    };
+
+    run("name");
+    touch_binary(60);
+    run("name");
+    let log = fs::read_to_string(dir.path().join("starship.log")).unwrap();
+    assert_eq!(log, "preset test-preset\n");
+
+    // The default key notices the re-fetched binary
+    run("binary");
+    touch_binary(120);
+    run("binary");
+    let log = fs::read_to_string(dir.path().join("starship.log")).unwrap();
+    let preset_runs = log.lines().filter(|l| *l == "preset test-preset").count();
+    assert_eq!(preset_runs, 3, "unexpected starship calls:\n{log}");
+}
+
+#[test]
+fn preset_auto_refresh_removes_stale_presets() {
+    let dir = TempDir::new().unwrap();
+    let stub = write_starship_stub(&dir, &[("test-preset", "format = \"$all\"\n")]);
+    let cache_dir = dir.path().join("cache");
+    let user_config = write_toml(&dir, "user.toml", "add_newline = false\n");
+
+    let run = || {
+        cmd()
+            .env("PATH", &stub)
+            .env("STARSHIP_MULTI_CACHE_DIR", &cache_dir)
+            .env("STARSHIP_PRESET_AUTO_REFRESH", "1")
+            .args(["--preset", "test-preset", &user_config])
+            .assert()
+            .success();
+    };
+
+    run();
+    // A preset cached for another invocation
+    let other = cache_dir.join("preset-0123abcd.toml");
+    fs::write(&other, "format = \"$all\"\n").unwrap();
+    run();
+    assert!(other.exists());
+
+    fs::write(dir.path().join("version"), "starship 1.1.0\n").unwrap();
+    run();
+    assert!(!other.exists());
+
+    let log = fs::read_to_string(dir.path().join("starship.log")).unwrap();
+    let preset_runs = log.lines().filter(|l| *l == "preset test-preset").count();
+    assert_eq!(preset_runs, 2, "unexpected starship calls:\n{log}");
+}
+
 #[test]
 fn multiple_presets() {
     let dir = TempDir::new().unwrap();